@@ -0,0 +1,95 @@
+//! Times drawing one frame at 4K with small cells, single threaded against one thread per core,
+//! and copying the glyphs from the contiguous atlas against the per-glyph `Vec<Vec<Vec<u8>>>` it replaced.
+//! Run with `cargo bench --bench render`.
+
+use std::{hint::black_box, time::{Duration, Instant}};
+
+use ffmpeg_the_third::format::Pixel;
+use video_filter_rs::{CharSet, CharSetBuilder, ColorMode, FrameRenderer, RenderData, RenderInput, RenderOptions};
+
+const DST_W: u32 = 3840;
+const DST_H: u32 = 2160;
+const RENDER_H: u32 = 240;
+const FRAMES: u32 = 100;
+
+fn char_set() -> CharSet {
+    CharSetBuilder::new(" .-^~:/*=+?%##&$$@", DST_H / RENDER_H)
+        .build_from_file("./MonospaceTypewriter.ttf")
+        .expect("Couldn't load the font")
+}
+
+fn time_frame(color_mode: ColorMode, render_threads: usize) -> Duration {
+    let char_set = char_set();
+    let render_w = DST_W / char_set.glyph_width();
+    let options = RenderOptions { color_mode, render_threads, ..Default::default() };
+    let mut renderer = FrameRenderer::new(char_set, RenderData::new(render_w, RENDER_H, DST_W, DST_H), Pixel::YUV420P, options);
+
+    // Gradient so every glyph of the set gets drawn
+    let cells = (render_w * RENDER_H) as usize;
+    let planes: [Vec<u8>; 3] = [7, 3, 5].map(|step| (0..cells).map(|i| (i * step % 256) as u8).collect());
+    let input = RenderInput {
+        planes: [&planes[0], &planes[1], &planes[2]],
+        strides: [render_w as usize; 3],
+        detail: None,
+    };
+
+    renderer.render_input(&input);
+    let start_t = Instant::now();
+    for _ in 0..FRAMES {
+        black_box(renderer.render_input(&input));
+    }
+    start_t.elapsed() / FRAMES
+}
+
+/// Copies the glyph of every cell line by line onto a luma plane, the way the renderer does on one thread.
+/// `glyph` returns the lines of a glyph, the only thing that differs between the two layouts.
+fn time_layout<'a, I: Iterator<Item = &'a [u8]>>(char_set: &CharSet, glyph: impl Fn(usize) -> I) -> Duration {
+    let (glyph_w, glyph_h) = (char_set.glyph_width() as usize, char_set.glyph_height() as usize);
+    let (cols, rows) = (DST_W as usize / glyph_w, RENDER_H as usize);
+    let stride = DST_W as usize;
+    let mut plane = vec![0; stride * rows * glyph_h];
+    let char_idx: Vec<usize> = (0..cols * rows).map(|i| i * 7 % char_set.len()).collect();
+
+    let start_t = Instant::now();
+    for _ in 0..FRAMES {
+        for row in 0..rows {
+            for col in 0..cols {
+                let mut start = row * glyph_h * stride + col * glyph_w;
+                for line in glyph(char_idx[row * cols + col]) {
+                    plane[start..start + line.len()].copy_from_slice(line);
+                    start += stride;
+                }
+            }
+        }
+        black_box(&mut plane);
+    }
+    start_t.elapsed() / FRAMES
+}
+
+fn main() {
+    for color_mode in [ColorMode::Gray, ColorMode::Cell] {
+        let single = time_frame(color_mode, 1);
+        let parallel = time_frame(color_mode, 0);
+        println!(
+            "{color_mode:?}: {:.2} ms/frame on 1 thread, {:.2} ms/frame on every core ({:.1}x)",
+            single.as_secs_f64() * 1000.,
+            parallel.as_secs_f64() * 1000.,
+            single.as_secs_f64() / parallel.as_secs_f64(),
+        );
+    }
+
+    // Same glyphs, one allocation per line against one allocation for the whole set
+    let char_set = char_set();
+    let glyph_w = char_set.glyph_width() as usize;
+    let nested: Vec<Vec<Vec<u8>>> = (0..char_set.len())
+        .map(|idx| char_set.glyph(idx).chunks_exact(glyph_w).map(<[u8]>::to_vec).collect())
+        .collect();
+    let per_glyph = time_layout(&char_set, |idx| nested[idx].iter().map(Vec::as_slice));
+    let atlas = time_layout(&char_set, |idx| char_set.glyph(idx).chunks_exact(glyph_w));
+    println!(
+        "Glyph copies on 1 thread: {:.2} ms/frame from per-glyph vectors, {:.2} ms/frame from the atlas ({:.1}x)",
+        per_glyph.as_secs_f64() * 1000.,
+        atlas.as_secs_f64() * 1000.,
+        per_glyph.as_secs_f64() / atlas.as_secs_f64(),
+    );
+}
@@ -0,0 +1,173 @@
+use ffmpeg_the_third::{codec::{self, Capabilities, Parameters}, decoder, encoder, filter, format, frame, ChannelLayout, Codec, Dictionary, Packet, Rational, Stream};
+
+use crate::{streams::{copy_stream_tags, OutputStream}, Error};
+
+/// Re-encodes an audio stream into a codec the output container accepts,
+/// resampling it to a rate, sample format and frame size the encoder supports
+pub(crate) struct AudioTranscoder {
+    in_stream_tb: Rational,
+    /// One over the input sample rate, packets are decoded and filtered in it
+    decoder_tb: Rational,
+    decoder: decoder::Audio,
+    graph: filter::Graph,
+    encoder: encoder::Audio,
+    encoder_tb: Rational,
+    out_stream_idx: usize,
+    out_stream_tb: Rational,
+    decoded: frame::Audio,
+    filtered: frame::Audio,
+}
+impl AudioTranscoder {
+    /// Adds the output stream to `out_ctx`, `bit_rate` of 0 keeps the encoder default.
+    /// The stream is only added once the encoder is open, so a failure leaves `out_ctx` untouched.
+    pub(crate) fn new(in_stream: &Stream, codec: Codec, bit_rate: usize, out_ctx: &mut format::context::Output) -> Result<Self, Error> {
+        let decoder = codec::Context::from_parameters(in_stream.parameters())?
+            .decoder().audio()?;
+        let capabilities = codec.audio()?;
+
+        // Keeps the source rate and sample format when the encoder supports them
+        let rate = match capabilities.rates() {
+            Some(rates) => {
+                let rates: Vec<i32> = rates.collect();
+                [decoder.rate() as i32, 48000].into_iter().find(|rate| rates.contains(rate)).unwrap_or(rates[0])
+            }
+            None => decoder.rate() as i32,
+        };
+        let sample_format = match capabilities.formats() {
+            Some(formats) => {
+                let formats: Vec<format::Sample> = formats.collect();
+                if formats.contains(&decoder.format()) {decoder.format()} else {formats[0]}
+            }
+            None => decoder.format(),
+        };
+        // Encoders limited to mono or stereo get a downmix
+        let channels = decoder.ch_layout().channels();
+        let default_layout = ChannelLayout::default_for_channels(channels);
+        let channel_layout = match capabilities.ch_layouts() {
+            Some(layouts) => {
+                let layouts: Vec<ChannelLayout> = layouts.collect();
+                if layouts.contains(&default_layout) {
+                    default_layout
+                } else {
+                    // The closest count below the source, more channels only when nothing smaller is supported
+                    layouts.into_iter()
+                        .min_by_key(|layout| (layout.channels() > channels, layout.channels().abs_diff(channels)))
+                        .unwrap_or(default_layout)
+                }
+            }
+            None => default_layout,
+        };
+
+        let global_header = out_ctx.format().flags().contains(format::Flags::GLOBAL_HEADER);
+        let encoder_tb = Rational(1, rate);
+        let mut encoder = codec::Context::new_with_codec(codec)
+            .encoder().audio()?;
+        encoder.set_rate(rate);
+        encoder.set_ch_layout(channel_layout);
+        encoder.set_format(sample_format);
+        encoder.set_time_base(encoder_tb);
+        if bit_rate > 0 {
+            encoder.set_bit_rate(bit_rate);
+        }
+        if global_header {
+            encoder.set_flags(codec::Flags::GLOBAL_HEADER);
+        }
+        let encoder = encoder.open_with(Dictionary::new())?;
+        let decoder_tb = Rational(1, decoder.rate() as i32);
+        let graph = filter_graph(&decoder, decoder_tb, &encoder, codec)?;
+
+        let mut out_stream = out_ctx.add_stream(codec)?;
+        let out_stream_idx = out_stream.index();
+        out_stream.set_time_base(encoder_tb);
+        out_stream.set_parameters(Parameters::from(&encoder));
+        copy_stream_tags(in_stream, &mut out_stream);
+        Ok(Self {
+            in_stream_tb: in_stream.time_base(),
+            decoder_tb,
+            decoder,
+            graph,
+            encoder,
+            encoder_tb,
+            out_stream_idx,
+            out_stream_tb: encoder_tb,
+            decoded: frame::Audio::empty(),
+            filtered: frame::Audio::empty(),
+        })
+    }
+
+    pub(crate) fn send_packet(&mut self, mut packet: Packet, out_ctx: &mut format::context::Output) -> Result<(), Error> {
+        packet.rescale_ts(self.in_stream_tb, self.decoder_tb);
+        self.decoder.send_packet(&packet)?;
+        self.decode(out_ctx)
+    }
+
+    /// Drains the decoder, the filters and the encoder
+    pub(crate) fn finish(&mut self, out_ctx: &mut format::context::Output) -> Result<(), Error> {
+        self.decoder.send_eof()?;
+        self.decode(out_ctx)?;
+        self.graph.get("in").expect("Audio graph has an input").source().flush()?;
+        self.filter(out_ctx)?;
+        self.encoder.send_eof()?;
+        self.encode(out_ctx)
+    }
+
+    fn decode(&mut self, out_ctx: &mut format::context::Output) -> Result<(), Error> {
+        while self.decoder.receive_frame(&mut self.decoded).is_ok() {
+            let timestamp = self.decoded.timestamp();
+            self.decoded.set_pts(timestamp);
+            self.graph.get("in").expect("Audio graph has an input").source().add(&self.decoded)?;
+            self.filter(out_ctx)?;
+        }
+        Ok(())
+    }
+
+    fn filter(&mut self, out_ctx: &mut format::context::Output) -> Result<(), Error> {
+        while self.graph.get("out").expect("Audio graph has an output").sink().frame(&mut self.filtered).is_ok() {
+            self.encoder.send_frame(&self.filtered)?;
+            self.encode(out_ctx)?;
+        }
+        Ok(())
+    }
+
+    fn encode(&mut self, out_ctx: &mut format::context::Output) -> Result<(), Error> {
+        let mut encoded = Packet::empty();
+        while self.encoder.receive_packet(&mut encoded).is_ok() {
+            encoded.rescale_ts(self.encoder_tb, self.out_stream_tb);
+            encoded.set_stream(self.out_stream_idx);
+            encoded.write_interleaved(out_ctx)?;
+        }
+        Ok(())
+    }
+}
+
+impl OutputStream for AudioTranscoder {
+    fn out_time_base_mut(&mut self) -> &mut Rational {
+        &mut self.out_stream_tb
+    }
+}
+
+/// Converts decoded frames to the rate, sample format, layout and frame size of the encoder
+fn filter_graph(decoder: &decoder::Audio, decoder_tb: Rational, encoder: &encoder::Audio, codec: Codec) -> Result<filter::Graph, Error> {
+    let mut graph = filter::Graph::new();
+    let args = format!(
+        "time_base={decoder_tb}:sample_rate={}:sample_fmt={}:channel_layout={}",
+        decoder.rate(), decoder.format().name(), decoder.ch_layout().description(),
+    );
+    let missing = |name: &str| Error::Config(format!("FFmpeg was built without the {name} filter"));
+    graph.add(&filter::find("abuffer").ok_or_else(|| missing("abuffer"))?, "in", &args)?;
+    graph.add(&filter::find("abuffersink").ok_or_else(|| missing("abuffersink"))?, "out", "")?;
+    {
+        let mut out = graph.get("out").expect("Audio graph has an output");
+        out.set_sample_format(encoder.format());
+        out.set_ch_layout(encoder.ch_layout());
+        out.set_sample_rate(encoder.rate());
+    }
+    graph.output("in", 0)?.input("out", 0)?.parse("anull")?;
+    graph.validate()?;
+
+    // Encoders like AAC only take frames of exactly frame_size samples
+    if !codec.capabilities().contains(Capabilities::VARIABLE_FRAME_SIZE) {
+        graph.get("out").expect("Audio graph has an output").sink().set_frame_size(encoder.frame_size());
+    }
+    Ok(graph)
+}
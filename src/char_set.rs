@@ -0,0 +1,369 @@
+use std::path::Path;
+
+use ab_glyph::{Font, FontRef, ScaleFont};
+
+use crate::Error;
+
+/// Columns of the coarse grid glyphs are compared on when matching shapes
+pub const SHAPE_W: usize = 4;
+/// Rows of the coarse grid glyphs are compared on when matching shapes
+pub const SHAPE_H: usize = 8;
+/// Number of entries a char set is expanded to when it is ordered by density
+const DENSITY_LEVELS: usize = 64;
+
+/// Rasterized glyph stamps, ordered from darkest to brightest, followed by the edge and text glyphs
+#[derive(Clone)]
+pub struct CharSet {
+    glyph_w: u32,
+    glyph_h: u32,
+    /// Number of glyphs in the density ramp
+    ramp_len: usize,
+    /// Index of the first text glyph, the edge glyphs sit between the ramp and it
+    text_start: usize,
+    chars: Vec<char>,
+    /// Every glyph stored back to back, `glyph_w * glyph_h` bytes each
+    atlas: Vec<u8>,
+    shapes: Vec<[u8; SHAPE_W * SHAPE_H]>,
+}
+impl CharSet {
+    pub fn glyph_width(&self) -> u32 {
+        self.glyph_w
+    }
+
+    pub fn glyph_height(&self) -> u32 {
+        self.glyph_h
+    }
+
+    /// Number of glyphs in the density ramp, edge and text glyphs are not counted
+    pub fn len(&self) -> usize {
+        self.ramp_len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ramp_len == 0
+    }
+
+    /// Index of the `n`th edge glyph, in the order they were given to [`CharSetBuilder::edge_chars`]
+    pub fn edge_index(&self, n: usize) -> Option<usize> {
+        (self.ramp_len + n < self.text_start).then_some(self.ramp_len + n)
+    }
+
+    /// Index of the text glyph drawing `c`, if it was given to [`CharSetBuilder::text_chars`]
+    pub fn text_index(&self, c: char) -> Option<usize> {
+        self.chars[self.text_start..].iter().position(|&t| t == c).map(|i| self.text_start + i)
+    }
+
+    /// Character drawn by the glyph at `idx`
+    pub fn char(&self, idx: usize) -> char {
+        self.chars[idx]
+    }
+
+    /// Row major coverage of the glyph at `idx`, `glyph_width` bytes per row
+    pub fn glyph(&self, idx: usize) -> &[u8] {
+        let size = (self.glyph_w * self.glyph_h) as usize;
+        &self.atlas[idx*size..(idx + 1)*size]
+    }
+
+    /// Average coverage of the glyph at `idx` on a row major [`SHAPE_W`] x [`SHAPE_H`] grid
+    pub fn shape(&self, idx: usize) -> &[u8; SHAPE_W * SHAPE_H] {
+        &self.shapes[idx]
+    }
+}
+
+/// Box filters a glyph down to the shape grid
+fn downsample(glyph: &[Vec<u8>], glyph_w: usize, glyph_h: usize) -> [u8; SHAPE_W * SHAPE_H] {
+    let mut shape = [0; SHAPE_W * SHAPE_H];
+    for sy in 0..SHAPE_H {
+        let top = sy * glyph_h / SHAPE_H;
+        let bottom = ((sy + 1) * glyph_h / SHAPE_H).max(top + 1).min(glyph_h);
+        for sx in 0..SHAPE_W {
+            let left = sx * glyph_w / SHAPE_W;
+            let right = ((sx + 1) * glyph_w / SHAPE_W).max(left + 1).min(glyph_w);
+            let sum: u32 = glyph[top..bottom]
+                .iter()
+                .flat_map(|line| &line[left..right])
+                .map(|&v| v as u32)
+                .sum();
+            shape[sy*SHAPE_W + sx] = (sum / ((bottom - top) * (right - left)) as u32) as u8;
+        }
+    }
+    shape
+}
+
+/// Sorts unique glyphs by ink coverage and resamples them into [`DENSITY_LEVELS`] entries,
+/// so that equal steps in luminance give equal steps in `coverage ^ (1 / gamma)`
+fn order_by_density(chars: Vec<char>, glyphs: Vec<Vec<Vec<u8>>>, gamma: f32) -> (Vec<char>, Vec<Vec<Vec<u8>>>) {
+    let mut measured: Vec<(f32, char, Vec<Vec<u8>>)> = Vec::with_capacity(glyphs.len());
+    for (c, glyph) in chars.into_iter().zip(glyphs) {
+        if measured.iter().any(|&(_, m, _)| m == c) {
+            continue;
+        }
+        let pixels = (glyph.len() * glyph[0].len()) as f32;
+        let coverage = glyph.iter().flatten().map(|&v| v as u32).sum::<u32>() as f32 / (pixels * 255.);
+        measured.push((coverage, c, glyph));
+    }
+    measured.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let min = measured[0].0;
+    let range = (measured[measured.len() - 1].0 - min).max(f32::EPSILON);
+    (0..DENSITY_LEVELS)
+        .map(|level| {
+            let target = (level as f32 / (DENSITY_LEVELS - 1) as f32).powf(gamma);
+            let distance = |coverage: f32| ((coverage - min) / range - target).abs();
+            let (_, c, glyph) = measured
+                .iter()
+                .min_by(|a, b| distance(a.0).total_cmp(&distance(b.0)))
+                .unwrap();
+            (*c, glyph.clone())
+        })
+        .unzip()
+}
+
+/// Builds a [`CharSet`] by rasterizing characters from a font
+pub struct CharSetBuilder {
+    chars: String,
+    font_h: u32,
+    font_thickness: f32,
+    align_baseline: bool,
+    anti_aliased: bool,
+    supersampling: u32,
+    /// Gamma of the density ramp, `None` keeps the order of `chars`
+    density_gamma: Option<f32>,
+    edge_chars: String,
+    text_chars: String,
+}
+impl CharSetBuilder {
+    /// `chars` are ordered from darkest to brightest, `font_h` is the height of a cell in pixels
+    pub fn new(chars: impl Into<String>, font_h: u32) -> Self {
+        Self {
+            chars: chars.into(),
+            font_h,
+            font_thickness: 0.25,
+            align_baseline: false,
+            anti_aliased: false,
+            supersampling: 1,
+            density_gamma: None,
+            edge_chars: String::new(),
+            text_chars: String::new(),
+        }
+    }
+
+    /// Coverage above which a pixel of a glyph is considered filled.
+    /// When anti-aliased, coverage is raised to the power of `4 * font_thickness` instead,
+    /// so 0.25 keeps it as is and lower values make strokes bolder.
+    pub fn font_thickness(mut self, font_thickness: f32) -> Self {
+        self.font_thickness = font_thickness;
+        self
+    }
+
+    /// Keeps glyphs at their height relative to the baseline instead of centering them vertically,
+    /// so that shapes like `_` and `-` stay distinguishable
+    pub fn align_baseline(mut self, align_baseline: bool) -> Self {
+        self.align_baseline = align_baseline;
+        self
+    }
+
+    /// Keeps the coverage of every pixel instead of thresholding it
+    pub fn anti_aliased(mut self, anti_aliased: bool) -> Self {
+        self.anti_aliased = anti_aliased;
+        self
+    }
+
+    /// Rasterizes glyphs at `factor` times their size and averages them back down
+    pub fn supersampling(mut self, factor: u32) -> Self {
+        self.supersampling = factor.max(1);
+        self
+    }
+
+    /// Ignores the order of `chars` and sorts the glyphs by their measured ink coverage instead.
+    /// Glyphs are repeated as needed so luminance maps linearly onto `coverage ^ (1 / gamma)`,
+    /// a gamma of 1 spaces coverage evenly. The gamma has to be greater than 0.
+    pub fn auto_density(mut self, gamma: f32) -> Self {
+        self.density_gamma = Some(gamma);
+        self
+    }
+
+    /// Characters drawn along edges, rasterized at the same size but kept out of the density ramp
+    pub fn edge_chars(mut self, chars: impl Into<String>) -> Self {
+        self.edge_chars = chars.into();
+        self
+    }
+
+    /// Characters used to write text such as subtitles. They are drawn on the baseline at the size
+    /// of the font that fits a cell, and don't affect how the other glyphs are scaled.
+    pub fn text_chars(mut self, chars: impl Into<String>) -> Self {
+        self.text_chars = chars.into();
+        self
+    }
+
+    /// Loads a ttf or otf font from disk and builds the char set with it
+    pub fn build_from_file(&self, font_path: impl AsRef<Path>) -> Result<CharSet, Error> {
+        let font_data = std::fs::read(font_path)?;
+        self.build(&font_data)
+    }
+
+    /// Keeps only the characters, for grids that are never drawn with the font such as terminal output.
+    /// Every glyph is a single blank pixel and `auto_density` is ignored since it measures the font.
+    pub fn build_without_font(&self) -> Result<CharSet, Error> {
+        if self.chars.is_empty() {
+            return Err(Error::Config("char_set must contain at least one character".into()));
+        }
+        let mut chars: Vec<char> = self.chars.chars().collect();
+        let ramp_len = chars.len();
+        chars.extend(self.edge_chars.chars());
+        let text_start = chars.len();
+        chars.extend(self.text_chars.chars());
+
+        Ok(CharSet {
+            glyph_w: 1,
+            glyph_h: 1,
+            ramp_len,
+            text_start,
+            shapes: vec![[0; SHAPE_W * SHAPE_H]; chars.len()],
+            atlas: vec![0; chars.len()],
+            chars,
+        })
+    }
+
+    pub fn build(&self, font_data: &[u8]) -> Result<CharSet, Error> {
+        if self.chars.is_empty() {
+            return Err(Error::Config("char_set must contain at least one character".into()));
+        }
+        if self.font_h == 0 {
+            return Err(Error::Config("Cells must be at least one pixel tall".into()));
+        }
+        // A gamma of 0 makes every target the densest glyph, a negative one makes them infinite
+        if let Some(gamma) = self.density_gamma.filter(|&gamma| gamma <= 0. || !gamma.is_finite()) {
+            return Err(Error::Config(format!("density_gamma must be greater than 0, got {gamma}")));
+        }
+        let font = FontRef::try_from_slice(font_data).map_err(|_| Error::InvalidFont)?;
+        let (font_h, font_thickness, align_baseline) = (self.font_h, self.font_thickness, self.align_baseline);
+        // font_thickness is a weight when anti-aliasing, so extents are measured at a fixed coverage
+        let measure_threshold = if self.anti_aliased {0.25} else {font_thickness};
+
+        // Determines proper font scaling
+        let func = |height| {
+            let scaled_font = font.as_scaled(height);
+            let glyphs: Vec<_> = self.chars.chars().chain(self.edge_chars.chars()).map(|x| scaled_font.outline_glyph(scaled_font.scaled_glyph(x))).collect();
+            let (mut top, mut bottom, mut left, mut right) = (i32::MAX, i32::MIN, i32::MAX, i32::MIN);
+            for glyph in glyphs.iter().flatten() {
+                // Measures from the baseline instead of the top of each glyph
+                let y_offset = if align_baseline {glyph.px_bounds().min.y as i32} else {0};
+                glyph.draw(|x, y, v| {
+                    if v > measure_threshold {
+                        top = top.min(y as i32 + y_offset);
+                        bottom = bottom.max(y as i32 + y_offset);
+                        left = left.min(x as i32);
+                        right = right.max(x as i32);
+                    }
+                });
+            }
+            if bottom < top {
+                return (0, 0, 0);
+            }
+            ((right - left) as u32, (bottom - top) as u32, top)
+        };
+        let (_, glyphs_height, _) = func(font_h as f32);
+        if glyphs_height == 0 {
+            return Err(Error::Config("No character in char_set has any visible pixels".into()));
+        }
+
+        let mut adj_font_h: f32 = font_h as f32 * font_h as f32 / glyphs_height as f32;
+        let (mut glyphs_width, mut glyphs_height, mut glyphs_top) = func(adj_font_h);
+
+        while glyphs_height > font_h {
+            adj_font_h *= font_h as f32 / glyphs_height as f32;
+            (glyphs_width, glyphs_height, glyphs_top) = func(adj_font_h);
+        }
+        let glyphs_width = glyphs_width.max(1);
+        let glyphs_height = glyphs_height.max(1);
+
+        // Render characters, at a multiple of their size when supersampling
+        let ss = self.supersampling;
+        let (ss_width, ss_height) = (glyphs_width * ss, glyphs_height * ss);
+        let scaled_font = font.as_scaled(adj_font_h * ss as f32);
+        let mut glyph_bytes: Vec<Vec<Vec<u8>>> = Vec::with_capacity(self.chars.len() + self.edge_chars.len());
+        // Averages the supersampled coverage of a glyph and applies the thickness to it
+        let to_bytes = |coverage: &[Vec<f32>]| -> Vec<Vec<u8>> {
+            (0..glyphs_height as usize)
+                .map(|y| (0..glyphs_width as usize)
+                    .map(|x| {
+                        let v = coverage[(y * ss as usize)..((y + 1) * ss as usize)]
+                            .iter()
+                            .flat_map(|line| &line[(x * ss as usize)..((x + 1) * ss as usize)])
+                            .sum::<f32>() / (ss * ss) as f32;
+                        if self.anti_aliased {
+                            (v.clamp(0., 1.).powf(4. * font_thickness) * 255.).round() as u8
+                        } else {
+                            (v > font_thickness) as u8 * 255
+                        }
+                    })
+                    .collect())
+                .collect()
+        };
+        for c in self.chars.chars().chain(self.edge_chars.chars()) {
+            let mut coverage = vec![vec![0f32; ss_width as usize]; ss_height as usize];
+            if let Some(g) = scaled_font.outline_glyph(scaled_font.scaled_glyph(c)) {
+                let bounding_box = g.px_bounds();
+                let x_pad = (ss_width as f32 - bounding_box.width()) / 2.;
+                let y_pad = if align_baseline {
+                    bounding_box.min.y - (glyphs_top * ss as i32) as f32
+                } else {
+                    (ss_height as f32 - bounding_box.height()) / 2.
+                };
+                g.draw(|x, y, v| {
+                    let x_i = x as f32 + x_pad;
+                    let y_i = y as f32 + y_pad;
+                    if x_i >= 0. && y_i >= 0. && (x_i as u32) < ss_width && (y_i as u32) < ss_height {
+                        coverage[y_i as usize][x_i as usize] = v;
+                    }
+                });
+            }
+
+            glyph_bytes.push(to_bytes(&coverage));
+        }
+
+        // Text is laid out on a common baseline with the whole line height fitting the cell
+        let text_font = font.as_scaled(glyphs_height as f32 * ss as f32);
+        let baseline = text_font.ascent();
+        let mut text_bytes = Vec::with_capacity(self.text_chars.len());
+        for c in self.text_chars.chars() {
+            let mut coverage = vec![vec![0f32; ss_width as usize]; ss_height as usize];
+            let glyph = text_font.scaled_glyph(c);
+            let x_pad = (ss_width as f32 - text_font.h_advance(glyph.id)) / 2.;
+            if let Some(g) = text_font.outline_glyph(glyph) {
+                let bounding_box = g.px_bounds();
+                g.draw(|x, y, v| {
+                    let x_i = x as f32 + bounding_box.min.x + x_pad;
+                    let y_i = y as f32 + bounding_box.min.y + baseline;
+                    if x_i >= 0. && y_i >= 0. && (x_i as u32) < ss_width && (y_i as u32) < ss_height {
+                        coverage[y_i as usize][x_i as usize] = v;
+                    }
+                });
+            }
+            text_bytes.push(to_bytes(&coverage));
+        }
+
+        let mut chars: Vec<char> = self.chars.chars().collect();
+        let edge_bytes = glyph_bytes.split_off(chars.len());
+        if let Some(gamma) = self.density_gamma {
+            (chars, glyph_bytes) = order_by_density(chars, glyph_bytes, gamma);
+        }
+        let ramp_len = chars.len();
+        chars.extend(self.edge_chars.chars());
+        glyph_bytes.extend(edge_bytes);
+        let text_start = chars.len();
+        chars.extend(self.text_chars.chars());
+        glyph_bytes.extend(text_bytes);
+
+        Ok(CharSet {
+            glyph_w: glyphs_width,
+            glyph_h: glyphs_height,
+            ramp_len,
+            text_start,
+            chars,
+            shapes: glyph_bytes.iter().map(|g| downsample(g, glyphs_width as usize, glyphs_height as usize)).collect(),
+            atlas: glyph_bytes.into_iter().flatten().flatten().collect(),
+        })
+    }
+}
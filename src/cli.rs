@@ -0,0 +1,308 @@
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand};
+use ini::Ini;
+
+/// Filters a video into ASCII art. Flags override the values in the settings file.
+#[derive(Parser)]
+#[command(version, about, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    /// Settings file to read instead of ./settings.ini
+    #[arg(short, long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Render flags can also be given without the `render` subcommand
+    #[command(flatten)]
+    pub render: RenderArgs,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Renders src into dst (default when no subcommand is given)
+    Render(Box<RenderArgs>),
+    /// Plays src in the terminal instead of writing a file
+    Play(Box<PlayArgs>),
+    /// Writes a settings file filled with the default values
+    Init {
+        /// Overwrite the settings file if it already exists
+        #[arg(short, long)]
+        force: bool,
+    },
+}
+
+#[derive(Args)]
+pub struct PlayArgs {
+    /// Columns the video is fitted into, the width of the terminal by default
+    #[arg(long)]
+    pub columns: Option<u32>,
+    /// Rows the video and its status line are fitted into, the height of the terminal by default
+    #[arg(long)]
+    pub rows: Option<u32>,
+    #[command(flatten)]
+    pub render: RenderArgs,
+}
+
+#[derive(Args)]
+pub struct RenderArgs {
+    /// Source video, still image, or image sequence like frame_%05d.png
+    #[arg(short, long)]
+    pub src: Option<String>,
+    /// Frames per second of an image sequence, e.g. 24 or 30000/1001. auto uses 25.
+    #[arg(long)]
+    pub src_frame_rate: Option<String>,
+    /// Destination video, none to only write text or HTML
+    #[arg(short, long)]
+    pub dst: Option<String>,
+    /// Characters of every frame as text, one file per frame when it contains a number like frame_%05d.txt
+    #[arg(long)]
+    pub text_dst: Option<String>,
+    /// Single HTML page that plays the characters back as text, with colors unless color_mode is gray
+    #[arg(long)]
+    pub html_dst: Option<String>,
+    /// Directory that a relative src is resolved against
+    #[arg(long)]
+    pub input_dir: Option<String>,
+    /// Directory that a relative dst is resolved against
+    #[arg(long)]
+    pub output_dir: Option<String>,
+    /// Video streams to filter: best, all, or the index of one stream. The others are copied.
+    #[arg(long)]
+    pub video_streams: Option<String>,
+    /// Height of the picture in pixels, burned subtitles add rows below it
+    #[arg(long)]
+    pub dst_h: Option<u32>,
+    /// Number of character rows of the picture
+    #[arg(long)]
+    pub render_h: Option<u32>,
+    /// Font used to draw the characters (ttf or otf)
+    #[arg(long)]
+    pub font_path: Option<String>,
+    /// Characters ordered from darkest to brightest ("space" is replaced by ' ')
+    #[arg(long)]
+    pub char_set: Option<String>,
+    /// Glyph coverage threshold, above 0 and below 1. With --anti-aliasing the stroke weight instead,
+    /// above 0 with 0.25 keeping the font as drawn and lower values bolder.
+    #[arg(long)]
+    pub font_thickness: Option<f32>,
+    /// Keep glyph edges smooth instead of thresholding them
+    #[arg(long)]
+    pub anti_aliasing: bool,
+    /// Rasterize glyphs at this multiple of their size and average them back down
+    #[arg(long)]
+    pub supersampling: Option<u32>,
+    /// Sort char_set by measured ink coverage instead of using it as ordered
+    #[arg(long)]
+    pub auto_density: bool,
+    /// gray, glyph to tint each character with the source color, or cell to also fill its background
+    #[arg(long)]
+    pub color_mode: Option<String>,
+    /// luminance, shape to pick the glyph that best matches the pixels under each cell,
+    /// or edge to draw lines along strong edges
+    #[arg(long)]
+    pub glyph_selection: Option<String>,
+    /// Mean gradient magnitude (0-255) above which a cell gets an edge glyph
+    #[arg(long)]
+    pub edge_threshold: Option<f32>,
+    /// Horizontal, vertical, rising and falling edge glyphs, optionally followed by a low horizontal one
+    #[arg(long, allow_hyphen_values = true)]
+    pub edge_chars: Option<String>,
+    /// none, floyd-steinberg, atkinson, bayer or blue-noise
+    #[arg(long)]
+    pub dither: Option<String>,
+    /// Added to the luminance, between -1 and 1
+    #[arg(long, allow_negative_numbers = true)]
+    pub brightness: Option<f32>,
+    /// Multiplies the distance of the luminance from mid gray
+    #[arg(long)]
+    pub contrast: Option<f32>,
+    /// Greater than 0, values above 1 brighten the mid tones
+    #[arg(long)]
+    pub gamma: Option<f32>,
+    /// off, frame to stretch every frame to the full range, or video to analyze the whole video first
+    #[arg(long)]
+    pub auto_levels: Option<String>,
+    /// Threads drawing the characters of a frame, 0 uses one per core
+    #[arg(long)]
+    pub render_threads: Option<usize>,
+    /// Threads FFmpeg decodes with, 0 lets it decide
+    #[arg(long)]
+    pub decoder_threads: Option<usize>,
+    /// Threads FFmpeg encodes with, 0 lets it decide
+    #[arg(long)]
+    pub encoder_threads: Option<usize>,
+    /// FFmpeg muxer, e.g. mp4, matroska, webm or nut. auto guesses it from the extension of dst.
+    #[arg(long)]
+    pub format: Option<String>,
+    /// FFmpeg encoder, e.g. libx265, libvpx-vp9, libsvtav1, ffv1, prores_ks or mjpeg.
+    /// auto picks the default of the output container.
+    #[arg(long)]
+    pub codec: Option<String>,
+    /// FFmpeg pixel format, e.g. yuv444p, gray, rgb24 or yuv420p10le. auto uses yuv420p when the encoder supports it,
+    /// and a palette chosen for every frame for GIF.
+    #[arg(long)]
+    pub pixel_format: Option<String>,
+    /// Dither colored frames that have more colors than a palette holds, with pixel_format pal8
+    #[arg(long)]
+    pub palette_dither: bool,
+    /// Times a GIF or APNG plays, 0 repeats it forever
+    #[arg(long)]
+    pub loop_count: Option<u32>,
+    /// Audio encoder, e.g. aac, libopus or flac, used on every audio stream.
+    /// auto only converts audio the container can't hold, to its default codec.
+    #[arg(long)]
+    pub audio_codec: Option<String>,
+    /// Bit rate of converted audio in bits per second, 0 keeps the encoder default
+    #[arg(long)]
+    pub audio_bit_rate: Option<usize>,
+    /// Draw the text subtitles into the bottom character rows instead of keeping them as a stream
+    #[arg(long)]
+    pub burn_subtitles: bool,
+    /// Character rows added below the picture for burned subtitles
+    #[arg(long)]
+    pub subtitle_rows: Option<u32>,
+    /// Encoder option, e.g. `-x crf=18 -x preset=slow`. Options the encoder doesn't have are reported and skipped,
+    /// encoders with a crf option default to crf=24.
+    #[arg(short = 'x', long = "encoder-option", visible_alias = "x264", value_name = "KEY=VALUE", value_parser = parse_key_val)]
+    pub encoder_options: Vec<(String, String)>,
+    /// Any other setting, e.g. `--set render_h=80` or `--set encoder_options.tune=animation`
+    #[arg(long = "set", value_name = "[SECTION.]KEY=VALUE", value_parser = parse_key_val)]
+    pub overrides: Vec<(String, String)>,
+}
+
+impl RenderArgs {
+    /// Writes every flag that was passed on top of the loaded settings
+    pub fn apply(&self, settings: &mut Ini) {
+        for (key, val) in &self.overrides {
+            match key.split_once('.') {
+                Some((section, key)) => settings.set_to(Some(section), key.to_owned(), val.to_owned()),
+                None => settings.set_to(None::<String>, key.to_owned(), val.to_owned()),
+            }
+        }
+
+        let mut base = settings.with_section(None::<String>);
+        if let Some(src) = &self.src {
+            base.set("src", src);
+        }
+        if let Some(src_frame_rate) = &self.src_frame_rate {
+            base.set("src_frame_rate", src_frame_rate);
+        }
+        if let Some(dst) = &self.dst {
+            base.set("dst", dst);
+        }
+        if let Some(text_dst) = &self.text_dst {
+            base.set("text_dst", text_dst);
+        }
+        if let Some(html_dst) = &self.html_dst {
+            base.set("html_dst", html_dst);
+        }
+        if let Some(input_dir) = &self.input_dir {
+            base.set("input_dir", input_dir);
+        }
+        if let Some(output_dir) = &self.output_dir {
+            base.set("output_dir", output_dir);
+        }
+        if let Some(video_streams) = &self.video_streams {
+            base.set("video_streams", video_streams);
+        }
+        if let Some(format) = &self.format {
+            base.set("format", format);
+        }
+        if let Some(codec) = &self.codec {
+            base.set("codec", codec);
+        }
+        if let Some(audio_codec) = &self.audio_codec {
+            base.set("audio_codec", audio_codec);
+        }
+        if let Some(audio_bit_rate) = self.audio_bit_rate {
+            base.set("audio_bit_rate", audio_bit_rate.to_string());
+        }
+        if self.burn_subtitles {
+            base.set("burn_subtitles", "true");
+        }
+        if let Some(subtitle_rows) = self.subtitle_rows {
+            base.set("subtitle_rows", subtitle_rows.to_string());
+        }
+        if let Some(pixel_format) = &self.pixel_format {
+            base.set("pixel_format", pixel_format);
+        }
+        if self.palette_dither {
+            base.set("palette_dither", "true");
+        }
+        if let Some(loop_count) = self.loop_count {
+            base.set("loop_count", loop_count.to_string());
+        }
+        if let Some(dst_h) = self.dst_h {
+            base.set("dst_h", dst_h.to_string());
+        }
+        if let Some(render_h) = self.render_h {
+            base.set("render_h", render_h.to_string());
+        }
+        if let Some(font_path) = &self.font_path {
+            base.set("font_path", font_path);
+        }
+        if let Some(char_set) = &self.char_set {
+            base.set("char_set", char_set);
+        }
+        if let Some(font_thickness) = self.font_thickness {
+            base.set("font_thickness", font_thickness.to_string());
+        }
+        if self.anti_aliasing {
+            base.set("anti_aliasing", "true");
+        }
+        if let Some(supersampling) = self.supersampling {
+            base.set("supersampling", supersampling.to_string());
+        }
+        if self.auto_density {
+            base.set("auto_density", "true");
+        }
+        if let Some(color_mode) = &self.color_mode {
+            base.set("color_mode", color_mode);
+        }
+        if let Some(glyph_selection) = &self.glyph_selection {
+            base.set("glyph_selection", glyph_selection);
+        }
+        if let Some(edge_threshold) = self.edge_threshold {
+            base.set("edge_threshold", edge_threshold.to_string());
+        }
+        if let Some(edge_chars) = &self.edge_chars {
+            base.set("edge_chars", edge_chars);
+        }
+        if let Some(dither) = &self.dither {
+            base.set("dither", dither);
+        }
+        if let Some(brightness) = self.brightness {
+            base.set("brightness", brightness.to_string());
+        }
+        if let Some(contrast) = self.contrast {
+            base.set("contrast", contrast.to_string());
+        }
+        if let Some(gamma) = self.gamma {
+            base.set("gamma", gamma.to_string());
+        }
+        if let Some(auto_levels) = &self.auto_levels {
+            base.set("auto_levels", auto_levels);
+        }
+        if let Some(render_threads) = self.render_threads {
+            base.set("render_threads", render_threads.to_string());
+        }
+        if let Some(decoder_threads) = self.decoder_threads {
+            base.set("decoder_threads", decoder_threads.to_string());
+        }
+        if let Some(encoder_threads) = self.encoder_threads {
+            base.set("encoder_threads", encoder_threads.to_string());
+        }
+
+        let mut encoder_options = settings.with_section(Some("encoder_options"));
+        for (key, val) in &self.encoder_options {
+            encoder_options.set(key, val);
+        }
+    }
+}
+
+fn parse_key_val(s: &str) -> Result<(String, String), String> {
+    let (key, val) = s.split_once('=').ok_or_else(|| format!("expected KEY=VALUE, got `{s}`"))?;
+    Ok((key.trim().to_owned(), val.trim().to_owned()))
+}
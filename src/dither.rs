@@ -0,0 +1,243 @@
+use std::str::FromStr;
+
+use crate::Error;
+
+/// How luminance is spread over glyph indices before characters are chosen
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Dither {
+    #[default]
+    None,
+    FloydSteinberg,
+    Atkinson,
+    /// 8x8 ordered dither
+    Bayer,
+    /// 64x64 void-and-cluster threshold map
+    BlueNoise,
+}
+impl FromStr for Dither {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Dither::None),
+            "floyd-steinberg" => Ok(Dither::FloydSteinberg),
+            "atkinson" => Ok(Dither::Atkinson),
+            "bayer" => Ok(Dither::Bayer),
+            "blue-noise" => Ok(Dither::BlueNoise),
+            _ => Err(Error::Config(format!("Unknown dither {s}, expected none, floyd-steinberg, atkinson, bayer or blue-noise"))),
+        }
+    }
+}
+
+/// (dx, dy, weight) of the neighbours that receive the quantization error
+const FLOYD_STEINBERG: [(isize, usize, f32); 4] = [(1, 0, 7. / 16.), (-1, 1, 3. / 16.), (0, 1, 5. / 16.), (1, 1, 1. / 16.)];
+const ATKINSON: [(isize, usize, f32); 6] = [(1, 0, 0.125), (2, 0, 0.125), (-1, 1, 0.125), (0, 1, 0.125), (1, 1, 0.125), (0, 2, 0.125)];
+
+/// Quantizes luminance into glyph indices
+pub(crate) struct Ditherer {
+    method: Dither,
+    /// Row major `map_size` x `map_size` thresholds between 0 and 1 for ordered dithering
+    threshold_map: Vec<f32>,
+    map_size: usize,
+    errors: Vec<f32>,
+    /// Glyph steps a cell has to move away from its previous index before it changes
+    stability: f32,
+    has_prev: bool,
+}
+impl Ditherer {
+    pub(crate) fn new(method: Dither, stability: f32) -> Self {
+        let (map_size, threshold_map) = match method {
+            Dither::Bayer => (8, bayer(8)),
+            Dither::BlueNoise => (64, blue_noise(64)),
+            _ => (1, vec![0.5]),
+        };
+        Self {
+            method,
+            threshold_map,
+            map_size,
+            errors: Vec::new(),
+            stability,
+            has_prev: false,
+        }
+    }
+
+    /// Maps a `r_w` x `r_h` luma buffer onto `levels` indices.
+    /// `char_idx` has to hold the indices of the previous frame for temporal stability.
+    pub(crate) fn quantize(&mut self, luma: &[u8], stride: usize, r_w: usize, r_h: usize, levels: usize, char_idx: &mut [usize]) {
+        let scale = levels as f32 / 256.;
+        let max = (levels - 1) as f32;
+        // Values closer than this to the previous index keep it
+        let hysteresis = if self.has_prev {0.5 + self.stability} else {0.};
+        let pick = |v: f32, idx: &mut usize| {
+            let prev = *idx as f32;
+            // Indices past the ramp are edge glyphs and never stick
+            let q = if *idx < levels && (v - prev).abs() < hysteresis {prev} else {v.round().clamp(0., max)};
+            *idx = q as usize;
+            q
+        };
+
+        match self.method {
+            Dither::FloydSteinberg | Dither::Atkinson => {
+                let kernel: &[(isize, usize, f32)] = if self.method == Dither::Atkinson {&ATKINSON} else {&FLOYD_STEINBERG};
+                // Padded by 2 on every side that receives errors
+                let w = r_w + 4;
+                self.errors.clear();
+                self.errors.resize(w * (r_h + 2), 0.);
+                for y in 0..r_h {
+                    for x in 0..r_w {
+                        let v = luma[y*stride + x] as f32 * scale - 0.5 + self.errors[y*w + x + 2];
+                        let err = v - pick(v, &mut char_idx[y*r_w + x]);
+                        for &(dx, dy, weight) in kernel {
+                            self.errors[(y + dy)*w + (x + 2).wrapping_add_signed(dx)] += err * weight;
+                        }
+                    }
+                }
+            }
+            Dither::None | Dither::Bayer | Dither::BlueNoise => {
+                let n = self.map_size;
+                for y in 0..r_h {
+                    for x in 0..r_w {
+                        let threshold = self.threshold_map[(y % n)*n + x % n];
+                        let v = luma[y*stride + x] as f32 * scale + threshold - 1.;
+                        pick(v, &mut char_idx[y*r_w + x]);
+                    }
+                }
+            }
+        }
+        self.has_prev = true;
+    }
+}
+
+/// Recursive Bayer matrix, `n` has to be a power of 2
+fn bayer(n: usize) -> Vec<f32> {
+    let mut matrix = vec![0usize];
+    let mut size = 1;
+    while size < n {
+        let mut next = vec![0; size * size * 4];
+        for y in 0..size {
+            for x in 0..size {
+                let v = matrix[y*size + x] * 4;
+                next[y*size*2 + x] = v;
+                next[y*size*2 + x + size] = v + 2;
+                next[(y + size)*size*2 + x] = v + 3;
+                next[(y + size)*size*2 + x + size] = v + 1;
+            }
+        }
+        matrix = next;
+        size *= 2;
+    }
+    matrix.iter().map(|&v| (v as f32 + 0.5) / (n * n) as f32).collect()
+}
+
+/// Adds or removes a point from the energy of a toroidal void-and-cluster pattern
+fn splat(energy: &mut [f32], kernel: &[f32], n: usize, idx: usize, sign: f32) {
+    let (px, py) = (idx % n, idx / n);
+    for (i, e) in energy.iter_mut().enumerate() {
+        let (x, y) = (i % n, i / n);
+        *e += sign * kernel[((y + n - py) % n)*n + (x + n - px) % n];
+    }
+}
+
+fn extreme(energy: &[f32], on: &[bool], want: bool, tightest: bool) -> usize {
+    let candidates = energy.iter().enumerate().filter(|&(i, _)| on[i] == want);
+    let found = if tightest {
+        candidates.max_by(|a, b| a.1.total_cmp(b.1))
+    } else {
+        candidates.min_by(|a, b| a.1.total_cmp(b.1))
+    };
+    found.unwrap().0
+}
+
+/// Void-and-cluster threshold map of `n` x `n`
+fn blue_noise(n: usize) -> Vec<f32> {
+    let size = n * n;
+    let sigma = 1.5f32;
+    let kernel: Vec<f32> = (0..size)
+        .map(|i| {
+            let (x, y) = (i % n, i / n);
+            let (dx, dy) = (x.min(n - x) as f32, y.min(n - y) as f32);
+            (-(dx*dx + dy*dy) / (2. * sigma * sigma)).exp()
+        })
+        .collect();
+
+    // Deterministic white noise seed pattern covering a tenth of the map
+    let mut energy = vec![0.; size];
+    let mut on = vec![false; size];
+    let initial = size / 10;
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut placed = 0;
+    while placed < initial {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let idx = (state % size as u64) as usize;
+        if !on[idx] {
+            on[idx] = true;
+            splat(&mut energy, &kernel, n, idx, 1.);
+            placed += 1;
+        }
+    }
+
+    // Moves the tightest cluster into the largest void until the pattern settles
+    for _ in 0..size {
+        let cluster = extreme(&energy, &on, true, true);
+        on[cluster] = false;
+        splat(&mut energy, &kernel, n, cluster, -1.);
+        let void = extreme(&energy, &on, false, false);
+        on[void] = true;
+        splat(&mut energy, &kernel, n, void, 1.);
+        if void == cluster {
+            break;
+        }
+    }
+
+    // Ranks the seed points by removing clusters, then the rest by filling voids
+    let mut rank = vec![0; size];
+    let (mut seed_energy, mut seed_on) = (energy.clone(), on.clone());
+    for r in (0..initial).rev() {
+        let cluster = extreme(&seed_energy, &seed_on, true, true);
+        seed_on[cluster] = false;
+        splat(&mut seed_energy, &kernel, n, cluster, -1.);
+        rank[cluster] = r;
+    }
+    for r in initial..size {
+        let void = extreme(&energy, &on, false, false);
+        on[void] = true;
+        splat(&mut energy, &kernel, n, void, 1.);
+        rank[void] = r;
+    }
+    rank.iter().map(|&r| (r as f32 + 0.5) / size as f32).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bayer_2x2() {
+        assert_eq!(bayer(1), [0.5]);
+        assert_eq!(bayer(2), [0.125, 0.625, 0.875, 0.375]);
+    }
+
+    #[test]
+    fn bayer_uses_every_threshold_once() {
+        for n in [4, 8, 16] {
+            let mut matrix = bayer(n);
+            matrix.sort_by(f32::total_cmp);
+            let expected: Vec<f32> = (0..n * n).map(|v| (v as f32 + 0.5) / (n * n) as f32).collect();
+            assert_eq!(matrix, expected, "{n}x{n}");
+        }
+    }
+
+    #[test]
+    fn bayer_spreads_neighbours_apart() {
+        // Horizontally adjacent thresholds of a Bayer matrix are always half the range apart
+        let n = 8;
+        let matrix = bayer(n);
+        for row in matrix.chunks_exact(n) {
+            for pair in row.chunks_exact(2) {
+                assert_eq!((pair[0] - pair[1]).abs(), 0.5);
+            }
+        }
+    }
+}
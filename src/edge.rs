@@ -0,0 +1,121 @@
+use std::{f32::consts::FRAC_PI_8, str::FromStr};
+
+use crate::{char_set::{SHAPE_H, SHAPE_W}, Error};
+
+/// Kernel used to estimate image gradients
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EdgeOperator {
+    #[default]
+    Sobel,
+    /// More rotationally symmetric than Sobel, so diagonals are told apart more reliably
+    Scharr,
+}
+impl EdgeOperator {
+    /// Weights of the corner and center taps
+    fn weights(self) -> (f32, f32) {
+        match self {
+            EdgeOperator::Sobel => (1., 2.),
+            EdgeOperator::Scharr => (3., 10.),
+        }
+    }
+}
+impl FromStr for EdgeOperator {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sobel" => Ok(EdgeOperator::Sobel),
+            "scharr" => Ok(EdgeOperator::Scharr),
+            _ => Err(Error::Config(format!("Unknown edge_operator {s}, expected sobel or scharr"))),
+        }
+    }
+}
+
+/// Orientation of the edge running through a cell, in the order of the edge glyph set
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Direction {
+    Horizontal,
+    Vertical,
+    /// From bottom left to top right
+    Rising,
+    /// From top left to bottom right
+    Falling,
+    /// Horizontal edge along the bottom of the cell
+    Baseline,
+}
+
+/// Finds the dominant edge of every cell of a detail buffer
+pub(crate) struct EdgeDetector {
+    operator: EdgeOperator,
+    /// Mean gradient magnitude of a cell, on the 0-255 scale of the luma, below which it counts as flat
+    threshold: f32,
+    /// How consistently the gradients of a cell have to point the same way, between 0 and 1
+    coherence: f32,
+}
+impl EdgeDetector {
+    pub(crate) fn new(operator: EdgeOperator, threshold: f32, coherence: f32) -> Self {
+        Self { operator, threshold, coherence }
+    }
+
+    /// `detail` holds [`SHAPE_W`] x [`SHAPE_H`] luma samples per cell of the `r_w` x `r_h` grid
+    pub(crate) fn detect(&self, detail: &[u8], stride: usize, r_w: usize, r_h: usize, directions: &mut Vec<Option<Direction>>) {
+        let (w, h) = (r_w * SHAPE_W, r_h * SHAPE_H);
+        let (corner, center) = self.operator.weights();
+        // A full step between two neighbouring pixels gives a magnitude of 255
+        let norm = 2. * corner + center;
+        let at = |x: usize, y: usize, dx: isize, dy: isize| {
+            let x = x.saturating_add_signed(dx).min(w - 1);
+            let y = y.saturating_add_signed(dy).min(h - 1);
+            detail[y*stride + x] as f32
+        };
+
+        directions.clear();
+        for row in 0..r_h {
+            for col in 0..r_w {
+                // Structure tensor, summed magnitude and magnitude weighted row of the cell
+                let (mut gxx, mut gyy, mut gxy, mut mag_sum, mut y_sum) = (0., 0., 0., 0., 0.);
+                for sy in 0..SHAPE_H {
+                    for sx in 0..SHAPE_W {
+                        let (x, y) = (col*SHAPE_W + sx, row*SHAPE_H + sy);
+                        let gx = (corner * (at(x, y, 1, -1) - at(x, y, -1, -1))
+                            + center * (at(x, y, 1, 0) - at(x, y, -1, 0))
+                            + corner * (at(x, y, 1, 1) - at(x, y, -1, 1))) / norm;
+                        let gy = (corner * (at(x, y, -1, 1) - at(x, y, -1, -1))
+                            + center * (at(x, y, 0, 1) - at(x, y, 0, -1))
+                            + corner * (at(x, y, 1, 1) - at(x, y, 1, -1))) / norm;
+                        let mag = (gx*gx + gy*gy).sqrt();
+                        gxx += gx*gx;
+                        gyy += gy*gy;
+                        gxy += gx*gy;
+                        mag_sum += mag;
+                        y_sum += mag * (sy as f32 + 0.5);
+                    }
+                }
+                directions.push(self.classify(gxx, gyy, gxy, mag_sum, y_sum));
+            }
+        }
+    }
+
+    fn classify(&self, gxx: f32, gyy: f32, gxy: f32, mag_sum: f32, y_sum: f32) -> Option<Direction> {
+        let mean = mag_sum / (SHAPE_W * SHAPE_H) as f32;
+        if mag_sum == 0. || mean < self.threshold {
+            return None;
+        }
+        let coherence = ((gxx - gyy).powi(2) + 4. * gxy*gxy).sqrt() / (gxx + gyy);
+        if coherence < self.coherence {
+            return None;
+        }
+
+        // Dominant gradient direction with y pointing down, the edge runs perpendicular to it
+        let angle = 0.5 * (2. * gxy).atan2(gxx - gyy);
+        Some(if angle.abs() < FRAC_PI_8 {
+            Direction::Vertical
+        } else if angle.abs() > 3. * FRAC_PI_8 {
+            if y_sum / mag_sum >= SHAPE_H as f32 * 0.75 {Direction::Baseline} else {Direction::Horizontal}
+        } else if angle > 0. {
+            Direction::Rising
+        } else {
+            Direction::Falling
+        })
+    }
+}
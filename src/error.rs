@@ -0,0 +1,50 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    Ffmpeg(ffmpeg_the_third::Error),
+    Io(std::io::Error),
+    /// The font file could not be parsed as ttf or otf
+    InvalidFont,
+    /// The input has no stream that can be filtered
+    NoVideoStream,
+    /// A setting is missing or has an invalid value
+    Config(String),
+    /// The job was stopped through its [`CancelToken`](crate::CancelToken)
+    Cancelled,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Ffmpeg(e) => write!(f, "ffmpeg: {e}"),
+            Error::Io(e) => write!(f, "io: {e}"),
+            Error::InvalidFont => write!(f, "Could not construct font"),
+            Error::NoVideoStream => write!(f, "Could not find a proper video stream"),
+            Error::Config(msg) => write!(f, "{msg}"),
+            Error::Cancelled => write!(f, "Transcode was cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Ffmpeg(e) => Some(e),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ffmpeg_the_third::Error> for Error {
+    fn from(e: ffmpeg_the_third::Error) -> Self {
+        Error::Ffmpeg(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -0,0 +1,285 @@
+use std::{collections::HashMap, fmt::Write as _, fs::File, io::{BufWriter, Write}, path::Path};
+
+use crate::{ColorMode, Error, FrameRenderer};
+
+/// Page with the player, the frames are written in place of [`DATA_MARKER`]
+const TEMPLATE: &str = include_str!("player.html");
+const DATA_MARKER: &str = "/*DATA*/";
+/// Symbols written as a single character, larger ones are written as `~` and their decimal index.
+/// Digits and the `.`, `*` and `~` tokens are left out so they can follow any symbol.
+const SYMBOLS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz!#$%&()+,:;=";
+/// Frames encoded without reference to the one before, seeking decodes forward from the closest one
+const KEYFRAME_INTERVAL: u64 = 120;
+/// Shortest run of cells written as a count instead of one symbol per cell
+const MIN_RUN: usize = 3;
+
+/// Writes every rendered frame into a single HTML page that plays them back as selectable text.
+/// Frames are stored as indices into the characters and colors that were used, each frame only
+/// encoding the cells that changed since the previous one and runs of equal cells as counts.
+pub(crate) struct HtmlWriter {
+    file: BufWriter<File>,
+    /// Whether the cell colors are stored next to the characters
+    colored: bool,
+    frame: u64,
+    /// Milliseconds from the first frame
+    times: Vec<i64>,
+    first_millis: Option<i64>,
+    chars: Palette<char>,
+    /// Background and foreground, 4 bits per channel
+    colors: Palette<u32>,
+    cells: (Vec<u32>, Vec<u32>),
+    previous: (Vec<u32>, Vec<u32>),
+    grid: (u32, u32),
+    /// Height of a cell relative to its width
+    cell_aspect: f32,
+}
+
+impl HtmlWriter {
+    pub(crate) fn create(path: &Path, color_mode: ColorMode) -> Result<Self, Error> {
+        let title = path.file_stem().map_or("".into(), |stem| stem.to_string_lossy());
+        let (head, _) = TEMPLATE.split_once(DATA_MARKER).expect("The player has a data marker");
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(head.replace("{title}", &escape_html(&title)).as_bytes())?;
+        file.write_all(b"const FRAMES = [\n")?;
+        Ok(Self {
+            file,
+            colored: color_mode != ColorMode::Gray,
+            frame: 0,
+            times: Vec::new(),
+            first_millis: None,
+            chars: Palette::default(),
+            colors: Palette::default(),
+            cells: (Vec::new(), Vec::new()),
+            previous: (Vec::new(), Vec::new()),
+            grid: (0, 0),
+            cell_aspect: 2.,
+        })
+    }
+
+    /// Adds the frame the renderer chose most recently, shown at `millis`.
+    /// Frames without a timestamp are shown together with the one before.
+    pub(crate) fn write(&mut self, renderer: &FrameRenderer, millis: Option<i64>) -> Result<(), Error> {
+        if self.frame == 0 {
+            let render_data = renderer.render_data();
+            self.grid = (render_data.render_width(), render_data.render_height());
+            let char_set = renderer.char_set();
+            self.cell_aspect = char_set.glyph_height() as f32 / char_set.glyph_width() as f32;
+        }
+        let time = match (millis, self.first_millis) {
+            (Some(millis), Some(first)) => millis - first,
+            (Some(millis), None) => {
+                self.first_millis = Some(millis);
+                0
+            }
+            (None, _) => self.times.last().copied().unwrap_or(0),
+        };
+        self.times.push(time);
+
+        let (chars, colors) = &mut self.cells;
+        chars.clear();
+        chars.extend(renderer.chars().map(|c| self.chars.index(c)));
+        let key = self.frame.is_multiple_of(KEYFRAME_INTERVAL);
+        let mut line = String::from("[\"");
+        encode(chars, (!key).then_some(&self.previous.0[..]), &mut line);
+        if self.colored {
+            colors.clear();
+            colors.extend(renderer.colors().map(|[bg, fg]| self.colors.index(pack(bg) << 12 | pack(fg))));
+            line.push_str("\",\"");
+            encode(colors, (!key).then_some(&self.previous.1[..]), &mut line);
+        }
+        line.push_str("\"],\n");
+        self.file.write_all(line.as_bytes())?;
+
+        std::mem::swap(&mut self.cells, &mut self.previous);
+        self.frame += 1;
+        Ok(())
+    }
+
+    /// Writes the timing, the characters and colors used and the rest of the page
+    pub(crate) fn finish(mut self) -> Result<(), Error> {
+        let chars: String = self.chars.values.iter().collect();
+        let colors: Vec<String> = self.colors.values.iter().map(|color| format!("\"{color:06x}\"")).collect();
+        let times: Vec<String> = self.times.iter().map(i64::to_string).collect();
+        write!(
+            self.file,
+            "];\nconst DATA = {{cols: {}, rows: {}, cellAspect: {}, keyframeInterval: {KEYFRAME_INTERVAL}, colored: {}, chars: {}, colors: [{}], times: [{}]}};\n",
+            self.grid.0, self.grid.1, self.cell_aspect, self.colored, json_string(&chars), colors.join(","), times.join(","),
+        )?;
+        let (_, tail) = TEMPLATE.split_once(DATA_MARKER).expect("The player has a data marker");
+        self.file.write_all(tail.as_bytes())?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/// Values in the order they were first seen
+struct Palette<T> {
+    values: Vec<T>,
+    indices: HashMap<T, u32>,
+}
+impl<T> Default for Palette<T> {
+    fn default() -> Self {
+        Self { values: Vec::new(), indices: HashMap::new() }
+    }
+}
+impl<T: Copy + Eq + std::hash::Hash> Palette<T> {
+    fn index(&mut self, value: T) -> u32 {
+        *self.indices.entry(value).or_insert_with(|| {
+            self.values.push(value);
+            self.values.len() as u32 - 1
+        })
+    }
+}
+
+/// 12 bit RGB, written as a 3 digit CSS color
+fn pack([r, g, b]: [u8; 3]) -> u32 {
+    (r as u32 >> 4) << 8 | (g as u32 >> 4) << 4 | b as u32 >> 4
+}
+
+/// Appends `cells` to `out` as symbols, with `.n` for `n` cells kept from `previous` and `*n` for `n` more
+/// cells of the symbol before
+fn encode(cells: &[u32], previous: Option<&[u32]>, out: &mut String) {
+    let mut i = 0;
+    while i < cells.len() {
+        if let Some(previous) = previous {
+            let kept = cells[i..].iter().zip(&previous[i..]).take_while(|(cell, old)| cell == old).count();
+            if kept >= MIN_RUN {
+                let _ = write!(out, ".{kept}");
+                i += kept;
+                continue;
+            }
+        }
+        let symbol = cells[i];
+        match SYMBOLS.get(symbol as usize) {
+            Some(&c) => out.push(c as char),
+            None => {
+                let _ = write!(out, "~{symbol}");
+            }
+        }
+        let repeats = cells[i + 1..].iter().take_while(|&&cell| cell == symbol).count();
+        if repeats >= MIN_RUN {
+            let _ = write!(out, "*{repeats}");
+            i += repeats;
+        }
+        i += 1;
+    }
+}
+
+/// JavaScript string literal that is also safe inside a script element
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '<' | '>' | '&' | '\u{2028}' | '\u{2029}' => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c if c.is_control() => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Port of `decode` in the player, writing the cells of `code` over `out`
+    fn decode(code: &str, out: &mut [u32]) {
+        let code = code.as_bytes();
+        let (mut i, mut o) = (0, 0);
+        let number = |i: &mut usize| {
+            let start = *i;
+            while *i < code.len() && code[*i].is_ascii_digit() {
+                *i += 1;
+            }
+            std::str::from_utf8(&code[start..*i]).unwrap().parse::<usize>().unwrap()
+        };
+        while i < code.len() {
+            let c = code[i];
+            i += 1;
+            match c {
+                b'.' => o += number(&mut i),
+                b'*' => {
+                    let n = number(&mut i);
+                    let previous = out[o - 1];
+                    out[o..o + n].fill(previous);
+                    o += n;
+                }
+                b'~' => {
+                    out[o] = number(&mut i) as u32;
+                    o += 1;
+                }
+                c => {
+                    out[o] = SYMBOLS.iter().position(|&symbol| symbol == c).unwrap() as u32;
+                    o += 1;
+                }
+            }
+        }
+        assert_eq!(o, out.len(), "Code covers every cell");
+    }
+
+    fn encoded(cells: &[u32], previous: Option<&[u32]>) -> String {
+        let mut out = String::new();
+        encode(cells, previous, &mut out);
+        out
+    }
+
+    #[test]
+    fn player_uses_the_same_symbols() {
+        let (_, symbols) = TEMPLATE.split_once("const SYMBOLS = \"").unwrap();
+        let (symbols, _) = symbols.split_once('"').unwrap();
+        assert_eq!(symbols.as_bytes(), SYMBOLS);
+    }
+
+    #[test]
+    fn runs_start_at_min_run() {
+        assert_eq!(MIN_RUN, 3);
+        assert_eq!(encoded(&[0, 0, 0], None), "AAA");
+        assert_eq!(encoded(&[0, 0, 0, 0], None), "A*3");
+        assert_eq!(encoded(&[1, 0, 0, 0, 0, 0, 1], None), "BA*4B");
+    }
+
+    #[test]
+    fn kept_cells_start_at_min_run() {
+        let previous = [5, 5, 5, 5, 1, 2];
+        assert_eq!(encoded(&[5, 5, 0, 5, 1, 2], Some(&previous)), "FFA.3");
+        assert_eq!(encoded(&[5, 5, 5, 0, 1, 2], Some(&previous)), ".3ABC");
+        assert_eq!(encoded(&previous, Some(&previous)), ".6");
+    }
+
+    #[test]
+    fn large_symbols_are_written_as_numbers() {
+        assert_eq!(encoded(&[63, 64, 64, 64, 64, 1000], None), "=~64*3~1000");
+    }
+
+    #[test]
+    fn round_trips() {
+        // Runs, kept cells and symbols past the alphabet mixed over a few frames
+        let frames: Vec<Vec<u32>> = (0..12u32)
+            .map(|frame| (0..500u32).map(|cell| match (cell / 7 + frame) % 5 {
+                0 => 0,
+                1 => cell % 3,
+                2 => 64 + cell % 70,
+                3 => cell / 50,
+                _ => frame % 4,
+            }).collect())
+            .collect();
+        let mut decoded = vec![0; 500];
+        for (i, cells) in frames.iter().enumerate() {
+            let previous = (i > 0).then(|| &frames[i - 1][..]);
+            decode(&encoded(cells, previous), &mut decoded);
+            assert_eq!(&decoded, cells, "frame {i}");
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use std::str::FromStr;
+
+use crate::{Error, RenderOptions};
+
+/// Narrowest range auto-levels stretches, so flat frames aren't blown out to black or white
+const MIN_SPAN: f32 = 32.;
+
+/// Where the black and white points of the luminance come from
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AutoLevels {
+    /// The full 0-255 range is used
+    #[default]
+    Off,
+    /// Measured on every frame and smoothed over time
+    Frame,
+    /// Measured once from a histogram of the whole video
+    Video,
+}
+impl FromStr for AutoLevels {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(AutoLevels::Off),
+            "frame" => Ok(AutoLevels::Frame),
+            "video" => Ok(AutoLevels::Video),
+            _ => Err(Error::Config(format!("Unknown auto_levels {s}, expected off, frame or video"))),
+        }
+    }
+}
+
+/// Brightness, contrast, gamma and levels applied to the luma before glyphs are chosen
+pub(crate) struct Levels {
+    brightness: f32,
+    contrast: f32,
+    gamma: f32,
+    auto_levels: AutoLevels,
+    smoothing: f32,
+    clip: f32,
+    /// Current black and white points
+    range: Option<(f32, f32)>,
+    lut: [u8; 256],
+}
+impl Levels {
+    /// `None` when the options leave the luma untouched
+    pub(crate) fn new(options: &RenderOptions) -> Option<Self> {
+        if options.brightness == 0. && options.contrast == 1. && options.gamma == 1. && options.auto_levels == AutoLevels::Off {
+            return None;
+        }
+        let mut levels = Self {
+            brightness: options.brightness,
+            contrast: options.contrast,
+            gamma: options.gamma,
+            auto_levels: options.auto_levels,
+            smoothing: options.auto_levels_smoothing.clamp(0., 1.),
+            clip: options.auto_levels_clip,
+            range: None,
+            lut: [0; 256],
+        };
+        levels.rebuild();
+        Some(levels)
+    }
+
+    pub(crate) fn set_range(&mut self, black: u8, white: u8) {
+        self.range = Some(widen(black as f32, white as f32));
+        self.rebuild();
+    }
+
+    /// Measures the black and white points of a frame when they are tracked per frame
+    pub(crate) fn update(&mut self, luma: &[u8], stride: usize, width: usize, height: usize) {
+        if self.auto_levels != AutoLevels::Frame {
+            return;
+        }
+        let mut histogram = [0; 256];
+        accumulate(&mut histogram, luma, stride, width, height);
+        let (black, white) = histogram_range(&histogram, self.clip);
+        let (black, white) = (black as f32, white as f32);
+        self.range = Some(match self.range {
+            Some((prev_black, prev_white)) => widen(
+                prev_black * self.smoothing + black * (1. - self.smoothing),
+                prev_white * self.smoothing + white * (1. - self.smoothing),
+            ),
+            None => widen(black, white),
+        });
+        self.rebuild();
+    }
+
+    /// Writes the adjusted `width` x `height` buffer tightly packed into `dst`
+    pub(crate) fn apply(&self, luma: &[u8], stride: usize, width: usize, height: usize, dst: &mut Vec<u8>) {
+        dst.clear();
+        for row in luma.chunks(stride).take(height) {
+            dst.extend(row[..width].iter().map(|&v| self.lut[v as usize]));
+        }
+    }
+
+    fn rebuild(&mut self) {
+        let (black, white) = self.range.unwrap_or((0., 255.));
+        for (v, out) in self.lut.iter_mut().enumerate() {
+            let x = ((v as f32 - black) / (white - black)).clamp(0., 1.);
+            let x = ((x - 0.5) * self.contrast + 0.5 + self.brightness).clamp(0., 1.);
+            *out = (x.powf(1. / self.gamma) * 255.).round() as u8;
+        }
+    }
+}
+
+fn widen(black: f32, white: f32) -> (f32, f32) {
+    if white - black >= MIN_SPAN {
+        return (black, white);
+    }
+    let center = ((black + white) / 2.).clamp(MIN_SPAN / 2., 255. - MIN_SPAN / 2.);
+    (center - MIN_SPAN / 2., center + MIN_SPAN / 2.)
+}
+
+pub(crate) fn accumulate(histogram: &mut [u64; 256], luma: &[u8], stride: usize, width: usize, height: usize) {
+    for row in luma.chunks(stride).take(height) {
+        for &v in &row[..width] {
+            histogram[v as usize] += 1;
+        }
+    }
+}
+
+/// Black and white points that leave out the darkest and brightest `clip` fraction of pixels
+pub(crate) fn histogram_range(histogram: &[u64; 256], clip: f32) -> (u8, u8) {
+    let total: u64 = histogram.iter().sum();
+    if total == 0 {
+        return (0, 255);
+    }
+    let limit = (total as f64 * clip as f64) as u64;
+    let find = |values: &mut dyn Iterator<Item = usize>| {
+        let mut seen = 0;
+        for v in values {
+            seen += histogram[v];
+            if seen > limit {
+                return Some(v);
+            }
+        }
+        None
+    };
+    let black = find(&mut (0..256)).unwrap_or(0);
+    let white = find(&mut (0..256).rev()).unwrap_or(255);
+    (black as u8, white.max(black) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_range_clips_both_ends() {
+        let mut histogram = [0; 256];
+        histogram[0] = 1;
+        histogram[50..150].fill(1);
+        histogram[255] = 1;
+        assert_eq!(histogram_range(&histogram, 0.), (0, 255));
+        assert_eq!(histogram_range(&histogram, 0.01), (50, 149));
+        assert_eq!(histogram_range(&histogram, 0.1), (59, 140));
+    }
+
+    #[test]
+    fn histogram_range_of_flat_and_empty_histograms() {
+        assert_eq!(histogram_range(&[0; 256], 0.005), (0, 255));
+        let mut histogram = [0; 256];
+        histogram[100] = 10;
+        assert_eq!(histogram_range(&histogram, 0.), (100, 100));
+        assert_eq!(histogram_range(&histogram, 0.49), (100, 100));
+    }
+
+    #[test]
+    fn widen_keeps_wide_ranges() {
+        assert_eq!(widen(0., 255.), (0., 255.));
+        assert_eq!(widen(10., 10. + MIN_SPAN), (10., 10. + MIN_SPAN));
+    }
+
+    #[test]
+    fn widen_narrow_ranges_around_their_center() {
+        assert_eq!(widen(100., 110.), (105. - MIN_SPAN / 2., 105. + MIN_SPAN / 2.));
+        // Ranges at the ends are pushed back inside 0-255
+        assert_eq!(widen(0., 4.), (0., MIN_SPAN));
+        assert_eq!(widen(250., 255.), (255. - MIN_SPAN, 255.));
+    }
+}
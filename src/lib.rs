@@ -0,0 +1,31 @@
+//! Filters videos into ASCII art.
+//!
+//! [`CharSetBuilder`] rasterizes a font into glyph stamps, [`FrameRenderer`] draws a luma buffer
+//! with them and [`TranscodeJob`] runs the whole decode, render and encode process on a file.
+//! [`PlayJob`] plays a file in the terminal instead.
+
+mod audio;
+mod char_set;
+mod dither;
+mod edge;
+mod error;
+mod html;
+mod levels;
+mod palette;
+mod play;
+mod render;
+pub mod settings;
+mod streams;
+mod subtitle;
+mod text;
+mod transcode;
+
+pub use char_set::{CharSet, CharSetBuilder, SHAPE_H, SHAPE_W};
+pub use dither::Dither;
+pub use edge::EdgeOperator;
+pub use error::Error;
+pub use levels::AutoLevels;
+pub use play::{PlayJob, PlayStats};
+pub use streams::{StreamSelection, TrackFilter};
+pub use render::{CellColor, ColorMode, FrameRenderer, GlyphSelection, RenderData, RenderInput, RenderOptions};
+pub use transcode::{CancelToken, Progress, TranscodeJob, TranscodeOptions, VideoStreams};
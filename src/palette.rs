@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+
+use ffmpeg_the_third::{format::Pixel, frame};
+
+/// Colors a paletted frame holds
+const PALETTE_SIZE: usize = 256;
+/// Bits per channel of the lookup table from colors to their closest palette entry
+const LOOKUP_BITS: u32 = 5;
+
+/// Reduces RGB24 frames to PAL8 with a palette chosen for each frame.
+/// Rendered frames rarely have more than a few hundred colors, so most of them keep their exact colors
+/// and only busy colored frames are reduced by median cut.
+pub(crate) struct Quantizer {
+    /// Diffuses the error of colors that aren't in the palette to their neighbours
+    dither: bool,
+    histogram: HashMap<u32, u32>,
+    /// Palette index of every color at [`LOOKUP_BITS`] per channel, `u16::MAX` when not looked up yet
+    lookup: Vec<u16>,
+}
+
+impl Quantizer {
+    pub(crate) fn new(dither: bool) -> Self {
+        Self {
+            dither,
+            histogram: HashMap::new(),
+            lookup: vec![u16::MAX; 1 << (3 * LOOKUP_BITS)],
+        }
+    }
+
+    /// Paletted copy of `rgb`, with the same timestamp
+    pub(crate) fn quantize(&mut self, rgb: &frame::Video) -> frame::Video {
+        let (width, height) = (rgb.width() as usize, rgb.height() as usize);
+        let (src, src_stride) = (rgb.data(0), rgb.stride(0));
+        let row = |y: usize| &src[y * src_stride..y * src_stride + width * 3];
+
+        // Runs of equal pixels are counted once, rendered cells are mostly flat
+        self.histogram.clear();
+        for y in 0..height {
+            let mut pixels = row(y).chunks_exact(3).map(|p| key([p[0], p[1], p[2]])).peekable();
+            while let Some(color) = pixels.next() {
+                let mut count = 1;
+                while pixels.next_if_eq(&color).is_some() {
+                    count += 1;
+                }
+                *self.histogram.entry(color).or_default() += count;
+            }
+        }
+        let exact = self.histogram.len() <= PALETTE_SIZE;
+        let palette: Vec<[u8; 3]> = if exact {
+            self.histogram.keys().map(|&color| rgb_of(color)).collect()
+        } else {
+            median_cut(self.histogram.iter().map(|(&color, &count)| (rgb_of(color), count)).collect(), PALETTE_SIZE)
+        };
+
+        let mut out = frame::Video::new(Pixel::PAL8, rgb.width(), rgb.height());
+        out.set_pts(rgb.pts());
+        // The palette plane is 256 native endian ARGB words, its stride doesn't describe its size
+        let entries = unsafe { std::slice::from_raw_parts_mut((*out.as_mut_ptr()).data[1], PALETTE_SIZE * 4) };
+        for (entry, &[r, g, b]) in entries.chunks_exact_mut(4).zip(&palette) {
+            entry.copy_from_slice(&u32::from_be_bytes([0xff, r, g, b]).to_ne_bytes());
+        }
+
+        let dst_stride = out.stride(0);
+        let dst = out.data_mut(0);
+        if exact {
+            let indices: HashMap<u32, u8> = palette.iter().enumerate().map(|(i, &color)| (key(color), i as u8)).collect();
+            for y in 0..height {
+                let dst_row = &mut dst[y * dst_stride..y * dst_stride + width];
+                for (index, p) in dst_row.iter_mut().zip(row(y).chunks_exact(3)) {
+                    *index = indices[&key([p[0], p[1], p[2]])];
+                }
+            }
+            return out;
+        }
+
+        self.lookup.fill(u16::MAX);
+        if !self.dither {
+            for y in 0..height {
+                let dst_row = &mut dst[y * dst_stride..y * dst_stride + width];
+                for (index, p) in dst_row.iter_mut().zip(row(y).chunks_exact(3)) {
+                    *index = self.closest(&palette, [p[0] as i32, p[1] as i32, p[2] as i32]);
+                }
+            }
+            return out;
+        }
+
+        // Floyd-Steinberg, the errors of the current and next row are kept with a cell of padding on both sides
+        let mut errors = [vec![[0i32; 3]; width + 2], vec![[0i32; 3]; width + 2]];
+        for y in 0..height {
+            let [current, next] = &mut errors;
+            next.fill([0; 3]);
+            let dst_row = &mut dst[y * dst_stride..y * dst_stride + width];
+            for (x, (index, p)) in dst_row.iter_mut().zip(row(y).chunks_exact(3)).enumerate() {
+                let color: [i32; 3] = std::array::from_fn(|c| (p[c] as i32 + current[x + 1][c] / 16).clamp(0, 255));
+                *index = self.closest(&palette, color);
+                let chosen = palette[*index as usize];
+                for c in 0..3 {
+                    let error = color[c] - chosen[c] as i32;
+                    current[x + 2][c] += error * 7;
+                    next[x][c] += error * 3;
+                    next[x + 1][c] += error * 5;
+                    next[x + 2][c] += error;
+                }
+            }
+            errors.swap(0, 1);
+        }
+        out
+    }
+
+    /// Palette index closest to `color`, looked up at reduced precision
+    fn closest(&mut self, palette: &[[u8; 3]], color: [i32; 3]) -> u8 {
+        let shift = 8 - LOOKUP_BITS;
+        let slot = color.iter().fold(0, |slot, &channel| slot << LOOKUP_BITS | (channel as usize >> shift));
+        if self.lookup[slot] == u16::MAX {
+            let closest = palette.iter().enumerate()
+                .min_by_key(|(_, entry)| (0..3).map(|c| (entry[c] as i32 - color[c]).pow(2)).sum::<i32>())
+                .map_or(0, |(i, _)| i);
+            self.lookup[slot] = closest as u16;
+        }
+        self.lookup[slot] as u8
+    }
+}
+
+/// Up to `size` colors representing `colors`, each with the number of pixels that have it.
+/// The box of colors with the widest channel, weighted by its pixels, is split at its median until there are enough.
+fn median_cut(colors: Vec<([u8; 3], u32)>, size: usize) -> Vec<[u8; 3]> {
+    let mut boxes = vec![colors];
+    while boxes.len() < size {
+        let widest = boxes.iter().enumerate()
+            .filter(|(_, colors)| colors.len() > 1)
+            .map(|(i, colors)| {
+                let (channel, range) = (0..3)
+                    .map(|c| {
+                        let (min, max) = colors.iter().fold((u8::MAX, 0), |(min, max), (color, _)| (min.min(color[c]), max.max(color[c])));
+                        (c, max - min)
+                    })
+                    .max_by_key(|&(_, range)| range)
+                    .expect("Colors have 3 channels");
+                (i, channel, range as u64 * pixels(colors))
+            })
+            .max_by_key(|&(_, _, score)| score);
+        let Some((i, channel, _)) = widest else {
+            break;
+        };
+
+        let mut colors = boxes.swap_remove(i);
+        colors.sort_unstable_by_key(|(color, _)| color[channel]);
+        let half = pixels(&colors) / 2;
+        let mut seen = 0;
+        let median = colors.iter().position(|&(_, count)| {
+            seen += count as u64;
+            seen >= half
+        });
+        let split = median.map_or(1, |i| i + 1).clamp(1, colors.len() - 1);
+        let upper = colors.split_off(split);
+        boxes.push(colors);
+        boxes.push(upper);
+    }
+
+    boxes.iter()
+        .map(|colors| {
+            let total = pixels(colors).max(1);
+            std::array::from_fn(|c| {
+                let sum: u64 = colors.iter().map(|(color, count)| color[c] as u64 * *count as u64).sum();
+                ((sum + total / 2) / total) as u8
+            })
+        })
+        .collect()
+}
+
+fn pixels(colors: &[([u8; 3], u32)]) -> u64 {
+    colors.iter().map(|&(_, count)| count as u64).sum()
+}
+
+fn key([r, g, b]: [u8; 3]) -> u32 {
+    (r as u32) << 16 | (g as u32) << 8 | b as u32
+}
+
+fn rgb_of(key: u32) -> [u8; 3] {
+    [(key >> 16) as u8, (key >> 8) as u8, key as u8]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn few_colors_are_kept_exactly() {
+        let colors = vec![([1, 2, 3], 10), ([200, 0, 0], 1), ([0, 255, 7], 5)];
+        let mut palette = median_cut(colors.clone(), 3);
+        palette.sort_unstable();
+        let mut expected: Vec<_> = colors.iter().map(|&(color, _)| color).collect();
+        expected.sort_unstable();
+        assert_eq!(palette, expected);
+    }
+
+    #[test]
+    fn never_more_than_size() {
+        let colors: Vec<_> = (0..4096u32).map(|i| (rgb_of(i * 4099 % (1 << 24)), i % 13 + 1)).collect();
+        for size in [1, 2, 17, PALETTE_SIZE] {
+            assert_eq!(median_cut(colors.clone(), size).len(), size);
+        }
+        assert_eq!(median_cut(vec![([9, 9, 9], 3)], PALETTE_SIZE), [[9, 9, 9]]);
+    }
+
+    #[test]
+    fn boxes_are_pixel_weighted_averages() {
+        assert_eq!(median_cut(vec![([0, 0, 0], 3), ([100, 40, 8], 1)], 1), [[25, 10, 2]]);
+
+        // Split along red, the dark pair is averaged by its pixels and the lone bright color stays exact
+        let mut palette = median_cut(vec![([0, 0, 0], 1), ([10, 0, 0], 3), ([250, 0, 0], 4)], 2);
+        palette.sort_unstable();
+        assert_eq!(palette, [[8, 0, 0], [250, 0, 0]]);
+    }
+}
@@ -0,0 +1,207 @@
+use std::{fs::File, io::{self, Write}, process::{Command, Stdio}, sync::mpsc::{sync_channel, Receiver}, thread, time::{Duration, Instant}};
+
+use ffmpeg_the_third::{codec, ffi::AV_TIME_BASE, format::Pixel, Rational, Rescale};
+
+use crate::{transcode::{analyze_levels, best_video_stream, is_filterable, open_input, threading_config, Decoder, ScaledFrame}, AutoLevels, CancelToken, CharSetBuilder, Error, FrameRenderer, GlyphSelection, RenderData, TranscodeOptions, VideoStreams};
+
+/// Frames decoded ahead of the one on screen
+const PLAY_DEPTH: usize = 8;
+/// Frames that would be shown later than this after their time are dropped to catch up
+const MAX_LATENESS: Duration = Duration::from_millis(40);
+/// Height of a terminal cell relative to its width
+const CELL_ASPECT: f64 = 2.;
+
+/// Frames shown and dropped by a [`PlayJob`]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PlayStats {
+    pub shown: u64,
+    pub dropped: u64,
+    pub elapsed: Duration,
+}
+
+/// Plays the filtered video of `src` in the terminal with ANSI escape codes, paced by the timestamps of its frames.
+/// Characters are chosen like in a [`TranscodeJob`](crate::TranscodeJob) but never rasterized, so the font is not needed.
+pub struct PlayJob {
+    options: TranscodeOptions,
+    columns: Option<u32>,
+    rows: Option<u32>,
+    cancel: CancelToken,
+}
+impl PlayJob {
+    pub fn new(options: TranscodeOptions) -> Self {
+        Self {
+            options,
+            columns: None,
+            rows: None,
+            cancel: CancelToken::new(),
+        }
+    }
+
+    /// Columns the video is fitted into instead of the width of the terminal
+    pub fn columns(mut self, columns: u32) -> Self {
+        self.columns = Some(columns.max(1));
+        self
+    }
+
+    /// Rows the video and the status line below it are fitted into instead of the height of the terminal
+    pub fn rows(mut self, rows: u32) -> Self {
+        self.rows = Some(rows.max(2));
+        self
+    }
+
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    pub fn run(self) -> Result<PlayStats, Error> {
+        let options = &self.options;
+        let start_t = Instant::now();
+        ffmpeg_the_third::init()?;
+
+        if options.render.glyph_selection == GlyphSelection::Shape {
+            return Err(Error::Config("glyph_selection shape compares glyph bitmaps, play supports luminance and edge".into()));
+        }
+
+        // Input, a single stream is played
+        let mut in_ctx = open_input(options)?;
+        let in_stream = match options.video_streams {
+            VideoStreams::Index(idx) => in_ctx.stream(idx)
+                .filter(is_filterable)
+                .ok_or_else(|| Error::Config(format!("Stream {idx} of {} is not a video stream", options.src.display())))?,
+            VideoStreams::Best | VideoStreams::All => best_video_stream(&in_ctx).ok_or(Error::NoVideoStream)?,
+        };
+        let stream_idx = in_stream.index();
+        let in_tb = in_stream.time_base();
+        let mut decoder_ctx = codec::Context::from_parameters(in_stream.parameters())?;
+        decoder_ctx.set_threading(threading_config(options.decoder_threads));
+        let decoder = decoder_ctx.decoder().video()?;
+
+        // One row is kept for the status line
+        let (columns, rows) = match (self.columns, self.rows) {
+            (Some(columns), Some(rows)) => (columns, rows),
+            (columns, rows) => {
+                let (terminal_columns, terminal_rows) = terminal_size();
+                (columns.unwrap_or(terminal_columns), rows.unwrap_or(terminal_rows))
+            }
+        };
+        let (r_w, r_h) = fit_grid(decoder.width(), decoder.height(), decoder.aspect_ratio(), columns, rows - 1);
+
+        let mut char_set = CharSetBuilder::new(options.char_set.as_str(), 1);
+        if options.render.glyph_selection == GlyphSelection::Edge {
+            if !(4..=5).contains(&options.edge_chars.chars().count()) {
+                return Err(Error::Config("edge_chars must contain 4 or 5 characters".into()));
+            }
+            char_set = char_set.edge_chars(options.edge_chars.as_str());
+        }
+        let mut render = options.render.clone();
+        render.subtitle_rows = 0;
+        // Cells are a pixel each, the frame is never drawn
+        let mut renderer = FrameRenderer::new(char_set.build_without_font()?, RenderData::new(r_w, r_h, r_w, r_h), Pixel::GRAY8, render);
+        if options.render.auto_levels == AutoLevels::Video {
+            let (black, white) = analyze_levels(options, stream_idx, r_w, r_h, options.render.auto_levels_clip, &self.cancel)?;
+            renderer.set_levels_range(black, white);
+        }
+        let decoder = Decoder::new(decoder, &renderer)?;
+        let cancel = &self.cancel;
+
+        // Decoding and drawing run on their own threads, this one demuxes
+        let mut stats = thread::scope(|scope| -> Result<PlayStats, Error> {
+            let (packet_tx, packet_rx) = sync_channel(PLAY_DEPTH);
+            let (frame_tx, frame_rx) = sync_channel(PLAY_DEPTH);
+            let decode = scope.spawn(move || decoder.run(packet_rx, frame_tx));
+            let show = scope.spawn(move || show_frames(renderer, frame_rx, in_tb, cancel));
+
+            for (stream, packet) in in_ctx.packets().filter_map(Result::ok) {
+                // A closed channel means playback stopped or the decoder failed, which is returned when it is joined
+                if cancel.is_cancelled() || (stream.index() == stream_idx && packet_tx.send(packet).is_err()) {
+                    break;
+                }
+            }
+
+            drop(packet_tx);
+            decode.join().unwrap_or_else(|_| panic!("Decode stage panicked"))?;
+            show.join().unwrap_or_else(|_| panic!("Show stage panicked"))
+        })?;
+
+        stats.elapsed = start_t.elapsed();
+        if self.cancel.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        Ok(stats)
+    }
+}
+
+/// Draws every frame at the time given by its timestamp, counted from the first one.
+/// Frames that are already too late are dropped without choosing their characters.
+fn show_frames(mut renderer: FrameRenderer, frames: Receiver<ScaledFrame>, in_tb: Rational, cancel: &CancelToken) -> Result<PlayStats, Error> {
+    let color_mode = renderer.options().color_mode;
+    let mut stats = PlayStats::default();
+    let mut out = io::stdout().lock();
+    out.write_all(b"\x1b[2J")?;
+    let mut text = String::new();
+    // Wall clock time and timestamp of the first frame, in microseconds
+    let mut clock: Option<(Instant, i64)> = None;
+    let mut position = 0;
+    for scaled in frames {
+        if cancel.is_cancelled() {
+            break;
+        }
+        if let Some(pts) = scaled.frame.pts() {
+            let pts = pts.rescale(in_tb, Rational(1, AV_TIME_BASE));
+            let (start, first_pts) = *clock.get_or_insert((Instant::now(), pts));
+            let due = start + Duration::from_micros((pts - first_pts).max(0) as u64);
+            let now = Instant::now();
+            if now > due + MAX_LATENESS {
+                stats.dropped += 1;
+                continue;
+            }
+            thread::sleep(due.saturating_duration_since(now));
+            position = pts;
+        }
+
+        renderer.select(&scaled.input(color_mode));
+        text.clear();
+        text.push_str("\x1b[H");
+        renderer.write_ansi(&mut text);
+        text.push_str(&format!("\r\n\x1b[K{} {} dropped", clock_time(position), stats.dropped));
+        out.write_all(text.as_bytes())?;
+        out.flush()?;
+        stats.shown += 1;
+    }
+    out.write_all(b"\x1b[0m\r\n")?;
+    Ok(stats)
+}
+
+/// Largest grid within `columns` x `rows` that keeps the display aspect ratio of the video
+fn fit_grid(width: u32, height: u32, sample_aspect: Rational, columns: u32, rows: u32) -> (u32, u32) {
+    let sample_aspect = if sample_aspect.numerator() > 0 && sample_aspect.denominator() > 0 {f64::from(sample_aspect)} else {1.};
+    // Columns per row that keep the picture undistorted
+    let aspect = width as f64 * sample_aspect / height as f64 * CELL_ASPECT;
+    if (columns as f64) < rows as f64 * aspect {
+        (columns, ((columns as f64 / aspect).round() as u32).clamp(1, rows))
+    } else {
+        (((rows as f64 * aspect).round() as u32).clamp(1, columns), rows)
+    }
+}
+
+/// Columns and rows of the controlling terminal, asked from stty.
+/// Falls back to the COLUMNS and LINES variables, then to 80 x 24.
+fn terminal_size() -> (u32, u32) {
+    let stty = File::open("/dev/tty").ok().and_then(|tty| {
+        let output = Command::new("stty").arg("size").stdin(tty).stderr(Stdio::null()).output().ok()?;
+        let output = String::from_utf8(output.stdout).ok()?;
+        let (rows, columns) = output.trim().split_once(' ')?;
+        Some((columns.parse().ok()?, rows.parse().ok()?))
+    });
+    let env = |key| std::env::var(key).ok().and_then(|value| value.parse().ok());
+    match stty {
+        Some((columns, rows)) if columns > 0 && rows > 1 => (columns, rows),
+        _ => (env("COLUMNS").unwrap_or(80).max(1), env("LINES").unwrap_or(24).max(2)),
+    }
+}
+
+/// `hh:mm:ss` of a timestamp in microseconds
+fn clock_time(micros: i64) -> String {
+    let seconds = micros.max(0) / AV_TIME_BASE as i64;
+    format!("{:02}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
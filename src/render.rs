@@ -0,0 +1,754 @@
+use std::{ops::Range, str::FromStr, thread};
+
+use ffmpeg_the_third::{format::Pixel, frame};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+use crate::{char_set::{SHAPE_H, SHAPE_W}, dither::Ditherer, edge::{Direction, EdgeDetector}, levels::Levels, AutoLevels, CharSet, Dither, EdgeOperator, Error};
+
+/// Maps the cells of the render grid to pixel positions on the output frame
+pub struct RenderData {
+    r_w: usize,
+    r_h: usize,
+    dst_w: u32,
+    dst_h: u32,
+    x: Vec<usize>,
+    y: Vec<usize>,
+}
+impl RenderData {
+    pub fn new(r_w: u32, r_h: u32, dst_w: u32, dst_h: u32) -> Self {
+        Self {
+            r_w: r_w as usize,
+            r_h: r_h as usize,
+            dst_w,
+            dst_h,
+            x: (0..r_w).map(|x| (x as f32 * dst_w as f32 / r_w as f32) as usize).collect(),
+            y: (0..r_h).map(|x| (x as f32 * dst_h as f32 / r_h as f32) as usize).collect(),
+        }
+    }
+
+    /// Number of character columns
+    pub fn render_width(&self) -> u32 {
+        self.r_w as u32
+    }
+
+    /// Number of character rows
+    pub fn render_height(&self) -> u32 {
+        self.r_h as u32
+    }
+
+    pub fn dst_width(&self) -> u32 {
+        self.dst_w
+    }
+
+    pub fn dst_height(&self) -> u32 {
+        self.dst_h
+    }
+}
+
+/// How the output frame is colored
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorMode {
+    /// White glyphs on black
+    #[default]
+    Gray,
+    /// Glyphs are tinted with the color of the source pixels under their cell
+    Glyph,
+    /// Every cell is filled with a background color and the glyph is drawn over it in a foreground color
+    Cell,
+}
+impl ColorMode {
+    /// Format the source has to be scaled to before rendering
+    pub fn scaled_format(self) -> Pixel {
+        match self {
+            ColorMode::Gray => Pixel::GRAY8,
+            ColorMode::Glyph | ColorMode::Cell => Pixel::YUV444P,
+        }
+    }
+}
+impl FromStr for ColorMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gray" => Ok(ColorMode::Gray),
+            "glyph" => Ok(ColorMode::Glyph),
+            "cell" => Ok(ColorMode::Cell),
+            _ => Err(Error::Config(format!("Unknown color_mode {s}, expected gray, glyph or cell"))),
+        }
+    }
+}
+
+/// Color of the foreground or background of a cell in [`ColorMode::Cell`], derived from the source pixels under it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CellColor {
+    Source,
+    /// Source color at a quarter of its brightness and saturation
+    Dark,
+    /// Black or white, whichever is further from the source brightness
+    Contrast,
+    /// Inverted source color
+    Complement,
+    Black,
+    White,
+}
+impl CellColor {
+    fn pick(self, [y, u, v]: [u8; 3]) -> [u8; 3] {
+        match self {
+            CellColor::Source => [y, u, v],
+            CellColor::Dark => [y / 4, 96 + u / 4, 96 + v / 4],
+            CellColor::Contrast => if y < 128 {[255, 128, 128]} else {[0, 128, 128]},
+            CellColor::Complement => [255 - y, 255 - u, 255 - v],
+            CellColor::Black => [0, 128, 128],
+            CellColor::White => [255, 128, 128],
+        }
+    }
+}
+impl FromStr for CellColor {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "source" => Ok(CellColor::Source),
+            "dark" => Ok(CellColor::Dark),
+            "contrast" => Ok(CellColor::Contrast),
+            "complement" => Ok(CellColor::Complement),
+            "black" => Ok(CellColor::Black),
+            "white" => Ok(CellColor::White),
+            _ => Err(Error::Config(format!("Unknown cell color {s}, expected source, dark, contrast, complement, black or white"))),
+        }
+    }
+}
+
+/// How the character of a cell is chosen
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GlyphSelection {
+    /// Maps the average brightness of the cell onto the char set
+    #[default]
+    Luminance,
+    /// Picks the glyph whose coarse bitmap has the smallest squared error against the source pixels
+    Shape,
+    /// Draws an edge glyph matching the gradient direction in cells with strong edges,
+    /// and maps the luminance of flat cells like [`GlyphSelection::Luminance`]
+    Edge,
+}
+impl FromStr for GlyphSelection {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "luminance" => Ok(GlyphSelection::Luminance),
+            "shape" => Ok(GlyphSelection::Shape),
+            "edge" => Ok(GlyphSelection::Edge),
+            _ => Err(Error::Config(format!("Unknown glyph_selection {s}, expected luminance, shape or edge"))),
+        }
+    }
+}
+
+/// Settings of a [`FrameRenderer`] that don't depend on the video
+#[derive(Clone, Debug)]
+pub struct RenderOptions {
+    pub color_mode: ColorMode,
+    pub cell_foreground: CellColor,
+    pub cell_background: CellColor,
+    pub glyph_selection: GlyphSelection,
+    /// Applied to the luminance before glyphs are looked up
+    pub dither: Dither,
+    /// Glyph steps the luminance of a cell has to move before its character changes between frames,
+    /// 0 disables it
+    pub dither_stability: f32,
+    /// Added to the luminance, between -1 and 1
+    pub brightness: f32,
+    /// Multiplies the distance of the luminance from mid gray
+    pub contrast: f32,
+    /// Greater than 0, values above 1 brighten the mid tones
+    pub gamma: f32,
+    pub auto_levels: AutoLevels,
+    /// Weight of the previous black and white points in [`AutoLevels::Frame`], between 0 and 1
+    pub auto_levels_smoothing: f32,
+    /// Fraction of the darkest and brightest pixels ignored when measuring levels, at least 0 and below 0.5
+    pub auto_levels_clip: f32,
+    /// Threads drawing bands of cell rows in parallel, 0 uses one per core
+    pub render_threads: usize,
+    pub edge_operator: EdgeOperator,
+    /// Mean gradient magnitude, between 0 and 255, above which a cell gets an edge glyph
+    pub edge_threshold: f32,
+    /// How consistently the gradients of a cell have to agree on a direction, between 0 and 1
+    pub edge_coherence: f32,
+    /// Character rows at the bottom kept free of the picture for [`FrameRenderer::set_subtitle`].
+    /// They are part of [`RenderData::render_height`], so add them to the rows of the picture to keep its aspect ratio.
+    pub subtitle_rows: u32,
+}
+impl RenderOptions {
+    /// Whether [`RenderInput::detail`] has to be provided
+    pub fn needs_detail(&self) -> bool {
+        matches!(self.glyph_selection, GlyphSelection::Shape | GlyphSelection::Edge)
+    }
+}
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            color_mode: ColorMode::Gray,
+            cell_foreground: CellColor::Contrast,
+            cell_background: CellColor::Source,
+            glyph_selection: GlyphSelection::Luminance,
+            dither: Dither::None,
+            dither_stability: 0.,
+            brightness: 0.,
+            contrast: 1.,
+            gamma: 1.,
+            auto_levels: AutoLevels::Off,
+            auto_levels_smoothing: 0.9,
+            auto_levels_clip: 0.005,
+            render_threads: 0,
+            edge_operator: EdgeOperator::Sobel,
+            edge_threshold: 40.,
+            edge_coherence: 0.5,
+            subtitle_rows: 0,
+        }
+    }
+}
+
+/// Source buffers of one frame
+pub struct RenderInput<'a> {
+    /// Y, U and V planes at [`FrameRenderer::input_size`]. Only the Y plane is read in [`ColorMode::Gray`].
+    pub planes: [&'a [u8]; 3],
+    pub strides: [usize; 3],
+    /// Luma at [`FrameRenderer::detail_size`] and its stride, read when [`RenderOptions::needs_detail`]
+    pub detail: Option<(&'a [u8], usize)>,
+}
+
+/// Draws a buffer at render resolution as characters on an output frame
+pub struct FrameRenderer {
+    char_set: CharSet,
+    render_data: RenderData,
+    options: RenderOptions,
+    out_frame: frame::Video,
+    chroma_shift: (u32, u32),
+    char_idx: Vec<usize>,
+    /// Glyphs with distinct shapes, duplicates in the char set are only compared once
+    shape_candidates: Vec<usize>,
+    ditherer: Option<Ditherer>,
+    edges: Option<EdgeDetector>,
+    edge_directions: Vec<Option<Direction>>,
+    levels: Option<Levels>,
+    /// Luma and detail buffers after levels were applied
+    adjusted: (Vec<u8>, Vec<u8>),
+    /// YUV background and foreground of every cell
+    cell_colors: Vec<[[u8; 3]; 2]>,
+    /// Rows of the grid the picture is drawn on, the subtitle rows follow them
+    picture_rows: usize,
+    /// Glyphs of the subtitle rows
+    subtitle_idx: Vec<usize>,
+    threads: usize,
+    /// Workers drawing the bands, kept for the life of the renderer. `None` draws on the calling thread.
+    pool: Option<ThreadPool>,
+}
+impl FrameRenderer {
+    /// `dst_fmt` must be one of the formats [`FrameRenderer::supports`].
+    /// The chroma planes are left neutral in [`ColorMode::Gray`], GRAY8 only gets the luma of the colors.
+    pub fn new(char_set: CharSet, render_data: RenderData, dst_fmt: Pixel, options: RenderOptions) -> Self {
+        assert!(Self::supports(dst_fmt), "FrameRenderer can't draw {dst_fmt:?}");
+        assert!((options.subtitle_rows as usize) < render_data.r_h, "Subtitle rows leave no room for the picture");
+        let mut out_frame = frame::Video::new(dst_fmt, render_data.dst_w, render_data.dst_h);
+        let chroma_shift = if dst_fmt == Pixel::GRAY8 {
+            (0, 0)
+        } else {
+            out_frame.data_mut(1).fill(127);
+            out_frame.data_mut(2).fill(127);
+            (
+                out_frame.width().div_ceil(out_frame.plane_width(1)).ilog2(),
+                out_frame.height().div_ceil(out_frame.plane_height(1)).ilog2(),
+            )
+        };
+
+        let mut shape_candidates: Vec<usize> = Vec::new();
+        for idx in 0..char_set.len() {
+            if !shape_candidates.iter().any(|&c| char_set.shape(c) == char_set.shape(idx)) {
+                shape_candidates.push(idx);
+            }
+        }
+
+        let ditherer = (options.dither != Dither::None || options.dither_stability > 0.)
+            .then(|| Ditherer::new(options.dither, options.dither_stability));
+        let edges = (options.glyph_selection == GlyphSelection::Edge)
+            .then(|| EdgeDetector::new(options.edge_operator, options.edge_threshold, options.edge_coherence));
+        let threads = match options.render_threads {
+            0 => thread::available_parallelism().map_or(1, |threads| threads.get()),
+            threads => threads,
+        };
+        // Without workers every band is drawn on the calling thread, which is only slower
+        let pool = (threads > 1)
+            .then(|| ThreadPoolBuilder::new().num_threads(threads).thread_name(|i| format!("render-{i}")).build().ok())
+            .flatten();
+
+        // Subtitles are always white on black
+        let picture_rows = render_data.r_h - options.subtitle_rows as usize;
+        let subtitle_cells = render_data.r_w * options.subtitle_rows as usize;
+        let mut cell_colors = vec![[[0, 128, 128]; 2]; render_data.r_w * render_data.r_h];
+        cell_colors[render_data.r_w * picture_rows..].fill([[0, 128, 128], [255, 128, 128]]);
+        let blank = char_set.text_index(' ').unwrap_or(0);
+
+        Self {
+            char_set,
+            shape_candidates,
+            ditherer,
+            edges,
+            edge_directions: Vec::new(),
+            levels: Levels::new(&options),
+            adjusted: (Vec::new(), Vec::new()),
+            char_idx: vec![0; render_data.r_w * render_data.r_h],
+            cell_colors,
+            picture_rows,
+            subtitle_idx: vec![blank; subtitle_cells],
+            threads,
+            pool,
+            render_data,
+            options,
+            out_frame,
+            chroma_shift,
+        }
+    }
+
+    /// Whether frames can be drawn in `format` directly: GRAY8 and 8 bit planar YUV.
+    /// Other formats have to be converted from one of these.
+    pub fn supports(format: Pixel) -> bool {
+        matches!(
+            format,
+            Pixel::GRAY8 | Pixel::YUV410P | Pixel::YUV411P | Pixel::YUV420P | Pixel::YUV422P | Pixel::YUV440P | Pixel::YUV444P
+                | Pixel::YUVJ420P | Pixel::YUVJ422P | Pixel::YUVJ440P | Pixel::YUVJ444P
+        )
+    }
+
+    pub fn char_set(&self) -> &CharSet {
+        &self.char_set
+    }
+
+    pub fn render_data(&self) -> &RenderData {
+        &self.render_data
+    }
+
+    pub fn options(&self) -> &RenderOptions {
+        &self.options
+    }
+
+    /// Size of the planes expected in [`RenderInput::planes`], the render grid without the subtitle rows
+    pub fn input_size(&self) -> (u32, u32) {
+        (self.render_data.r_w as u32, self.picture_rows as u32)
+    }
+
+    /// Size of the luma buffer expected in [`RenderInput::detail`]
+    pub fn detail_size(&self) -> (u32, u32) {
+        ((self.render_data.r_w * SHAPE_W) as u32, (self.picture_rows * SHAPE_H) as u32)
+    }
+
+    /// Renders `luma`, a buffer of [`FrameRenderer::input_size`] whose rows are `stride` bytes apart.
+    /// Glyphs are chosen by luminance with the configured dither, without levels, shapes or edges,
+    /// and drawn white on black whatever the color mode.
+    pub fn render(&mut self, luma: &[u8], stride: usize) -> &mut frame::Video {
+        self.select_by_luma(luma, stride);
+        self.copy_subtitle();
+        self.blit(0, false);
+        // Colors of an earlier render_input are cleared, gray mode never draws the chroma
+        if self.options.color_mode != ColorMode::Gray && self.out_frame.format() != Pixel::GRAY8 {
+            self.out_frame.data_mut(1).fill(127);
+            self.out_frame.data_mut(2).fill(127);
+        }
+        &mut self.out_frame
+    }
+
+    /// Renders one frame according to the options
+    pub fn render_input(&mut self, input: &RenderInput) -> &mut frame::Video {
+        self.select(input);
+        self.paint();
+        &mut self.out_frame
+    }
+
+    /// Chooses the characters and colors of one frame without drawing them,
+    /// for when only [`FrameRenderer::write_text`] is needed
+    pub fn select(&mut self, input: &RenderInput) {
+        let Some(mut levels) = self.levels.take() else {
+            self.choose(input);
+            return;
+        };
+
+        // Adjusts the luma, and the detail used for matching shapes alongside it
+        let (r_w, r_h) = (self.render_data.r_w, self.picture_rows);
+        let (detail_w, detail_h) = self.detail_size();
+        let (mut luma, mut detail) = std::mem::take(&mut self.adjusted);
+        levels.update(input.planes[0], input.strides[0], r_w, r_h);
+        levels.apply(input.planes[0], input.strides[0], r_w, r_h, &mut luma);
+        if let Some((src, stride)) = input.detail {
+            levels.apply(src, stride, detail_w as usize, detail_h as usize, &mut detail);
+        }
+        self.choose(&RenderInput {
+            planes: [&luma, input.planes[1], input.planes[2]],
+            strides: [r_w, input.strides[1], input.strides[2]],
+            detail: input.detail.map(|_| (&detail[..], detail_w as usize)),
+        });
+
+        self.levels = Some(levels);
+        self.adjusted = (luma, detail);
+    }
+
+    /// Appends the characters of the most recent frame to `text`, one line per row
+    pub fn write_text(&self, text: &mut String) {
+        for row in self.char_idx.chunks_exact(self.render_data.r_w) {
+            text.extend(row.iter().map(|&idx| self.char_set.char(idx)));
+            text.push('\n');
+        }
+    }
+
+    /// Characters of the most recent frame, row by row
+    pub fn chars(&self) -> impl Iterator<Item = char> + '_ {
+        self.char_idx.iter().map(|&idx| self.char_set.char(idx))
+    }
+
+    /// RGB background and foreground of every cell of the most recent frame, row by row.
+    /// Only chosen when the color mode isn't [`ColorMode::Gray`].
+    pub fn colors(&self) -> impl Iterator<Item = [[u8; 3]; 2]> + '_ {
+        self.cell_colors.iter().map(|&colors| colors.map(yuv_to_rgb))
+    }
+
+    /// Appends the most recent frame to `text` for a terminal, rows separated by `\r\n`.
+    /// Cells get 24-bit foreground and background colors unless the color mode is [`ColorMode::Gray`].
+    pub fn write_ansi(&self, text: &mut String) {
+        let colored = self.options.color_mode != ColorMode::Gray;
+        for (row, cells) in self.char_idx.chunks_exact(self.render_data.r_w).enumerate() {
+            if row > 0 {
+                text.push_str("\r\n");
+            }
+            let mut last = None;
+            for (col, &idx) in cells.iter().enumerate() {
+                let colors = self.cell_colors[row*self.render_data.r_w + col];
+                if colored && last != Some(colors) {
+                    let [[br, bg, bb], [fr, fg, fb]] = colors.map(yuv_to_rgb);
+                    text.push_str(&format!("\x1b[48;2;{br};{bg};{bb};38;2;{fr};{fg};{fb}m"));
+                    last = Some(colors);
+                }
+                text.push(self.char_set.char(idx));
+            }
+            if colored {
+                text.push_str("\x1b[0m");
+            }
+        }
+    }
+
+    /// Fixes the black and white points, used with [`AutoLevels::Video`] after the video was analyzed
+    pub fn set_levels_range(&mut self, black: u8, white: u8) {
+        if let Some(levels) = self.levels.as_mut() {
+            levels.set_range(black, white);
+        }
+    }
+
+    /// Writes `text` into the subtitle rows of the frames rendered from now on, an empty text clears them.
+    /// Lines are wrapped at word boundaries, centered and aligned to the bottom. Lines that don't fit are left out,
+    /// as are characters without a glyph from [`CharSetBuilder::text_chars`](crate::CharSetBuilder::text_chars).
+    pub fn set_subtitle(&mut self, text: &str) {
+        let r_w = self.render_data.r_w;
+        let rows = self.options.subtitle_rows as usize;
+        let blank = self.char_set.text_index(' ').unwrap_or(0);
+        self.subtitle_idx.fill(blank);
+
+        let mut lines: Vec<Vec<usize>> = Vec::new();
+        for paragraph in text.lines() {
+            let mut line: Vec<usize> = Vec::new();
+            for word in paragraph.split_whitespace() {
+                let mut word: Vec<usize> = word.chars().filter_map(|c| self.char_set.text_index(c)).collect();
+                if !line.is_empty() && line.len() + 1 + word.len() > r_w {
+                    lines.push(std::mem::take(&mut line));
+                }
+                if !line.is_empty() {
+                    line.push(blank);
+                }
+                // Words longer than a whole row are broken anywhere
+                while line.len() + word.len() > r_w {
+                    let rest = word.split_off(r_w - line.len());
+                    line.append(&mut word);
+                    lines.push(std::mem::take(&mut line));
+                    word = rest;
+                }
+                line.append(&mut word);
+            }
+            if !line.is_empty() {
+                lines.push(line);
+            }
+        }
+
+        lines.truncate(rows);
+        let first_row = rows - lines.len();
+        for (row, line) in lines.iter().enumerate() {
+            let start = (first_row + row)*r_w + (r_w - line.len()) / 2;
+            self.subtitle_idx[start..(start + line.len())].copy_from_slice(line);
+        }
+    }
+
+    fn copy_subtitle(&mut self) {
+        let start = self.render_data.r_w * self.picture_rows;
+        self.char_idx[start..].copy_from_slice(&self.subtitle_idx);
+    }
+
+    fn choose(&mut self, input: &RenderInput) {
+        match self.options.glyph_selection {
+            GlyphSelection::Luminance => self.select_by_luma(input.planes[0], input.strides[0]),
+            GlyphSelection::Shape => {
+                let (detail, stride) = input.detail.expect("Shape matching needs a detail buffer");
+                self.select_by_shape(detail, stride);
+            }
+            GlyphSelection::Edge => {
+                self.select_by_luma(input.planes[0], input.strides[0]);
+                let (detail, stride) = input.detail.expect("Edge detection needs a detail buffer");
+                self.select_edges(detail, stride);
+            }
+        }
+        self.copy_subtitle();
+        if self.options.color_mode != ColorMode::Gray {
+            self.select_colors(input.planes, input.strides);
+        }
+    }
+
+    fn paint(&mut self) {
+        if self.options.color_mode == ColorMode::Gray {
+            self.blit(0, false);
+        } else {
+            let planes = if self.out_frame.format() == Pixel::GRAY8 {1} else {3};
+            for plane in 0..planes {
+                self.blit(plane, true);
+            }
+        }
+    }
+
+    /// The most recently rendered frame
+    pub fn frame(&self) -> &frame::Video {
+        &self.out_frame
+    }
+
+    fn select_by_luma(&mut self, luma: &[u8], stride: usize) {
+        let r_w = self.render_data.r_w;
+        let picture = &mut self.char_idx[..r_w * self.picture_rows];
+        if let Some(ditherer) = self.ditherer.as_mut() {
+            ditherer.quantize(luma, stride, r_w, self.picture_rows, self.char_set.len(), picture);
+            return;
+        }
+
+        let lum_to_char = self.char_set.len() as f32 / 256.;
+        for (row, char_idx) in picture.chunks_exact_mut(r_w).enumerate() {
+            let lum_row = &luma[row*stride..(row*stride + r_w)];
+            for (idx, lum) in char_idx.iter_mut().zip(lum_row) {
+                *idx = (*lum as f32 * lum_to_char) as usize;
+            }
+        }
+    }
+
+    fn select_by_shape(&mut self, detail: &[u8], stride: usize) {
+        let r_w = self.render_data.r_w;
+        let mut samples = [0; SHAPE_W * SHAPE_H];
+        for (row, char_idx) in self.char_idx[..r_w * self.picture_rows].chunks_exact_mut(r_w).enumerate() {
+            for (col, idx) in char_idx.iter_mut().enumerate() {
+                for (sy, line) in samples.chunks_exact_mut(SHAPE_W).enumerate() {
+                    let start = (row*SHAPE_H + sy)*stride + col*SHAPE_W;
+                    line.copy_from_slice(&detail[start..(start + SHAPE_W)]);
+                }
+                *idx = *self.shape_candidates
+                    .iter()
+                    .min_by_key(|&&c| {
+                        self.char_set.shape(c)
+                            .iter()
+                            .zip(&samples)
+                            .map(|(&g, &s)| (g as i32 - s as i32).pow(2) as u32)
+                            .sum::<u32>()
+                    })
+                    .unwrap();
+            }
+        }
+    }
+
+    /// Replaces the characters of cells with a strong edge by the edge glyph of its direction
+    fn select_edges(&mut self, detail: &[u8], stride: usize) {
+        let Some(edges) = self.edges.as_ref() else {
+            return;
+        };
+        edges.detect(detail, stride, self.render_data.r_w, self.picture_rows, &mut self.edge_directions);
+        for (idx, direction) in self.char_idx.iter_mut().zip(&self.edge_directions) {
+            let Some(direction) = *direction else {
+                continue;
+            };
+            // The baseline glyph is optional and falls back to the horizontal one
+            let glyph = match self.char_set.edge_index(direction as usize) {
+                None if direction == Direction::Baseline => self.char_set.edge_index(Direction::Horizontal as usize),
+                glyph => glyph,
+            };
+            if let Some(glyph) = glyph {
+                *idx = glyph;
+            }
+        }
+    }
+
+    fn select_colors(&mut self, planes: [&[u8]; 3], strides: [usize; 3]) {
+        let r_w = self.render_data.r_w;
+        let options = &self.options;
+        for (row, colors) in self.cell_colors[..r_w * self.picture_rows].chunks_exact_mut(r_w).enumerate() {
+            for (col, color) in colors.iter_mut().enumerate() {
+                let src = [0, 1, 2].map(|p| planes[p][row*strides[p] + col]);
+                *color = match options.color_mode {
+                    ColorMode::Gray => [[0, 128, 128], [255, 128, 128]],
+                    ColorMode::Glyph => [[0, 128, 128], src],
+                    ColorMode::Cell => [options.cell_background.pick(src), options.cell_foreground.pick(src)],
+                };
+            }
+        }
+    }
+
+    /// Draws the selected characters on a plane, splitting the cell rows into bands drawn in parallel by the pool.
+    /// Without `blend` the glyphs are copied as white on black, otherwise each cell's background and
+    /// foreground are blended using the glyph as coverage.
+    fn blit(&mut self, plane: usize, blend: bool) {
+        let r_h = self.render_data.r_h;
+        let shift = if plane == 0 {(0, 0)} else {self.chroma_shift};
+        let stride = self.out_frame.stride(plane);
+        let blitter = Blitter {
+            char_set: &self.char_set,
+            render_data: &self.render_data,
+            char_idx: &self.char_idx,
+            cell_colors: &self.cell_colors,
+            plane,
+            shift,
+            stride,
+            blend,
+        };
+        let bytes = self.out_frame.data_mut(plane);
+        let rows_per_band = r_h.div_ceil(self.threads.clamp(1, r_h.max(1)));
+        let Some(pool) = self.pool.as_ref().filter(|_| rows_per_band < r_h) else {
+            blitter.draw_rows(bytes, 0, 0..r_h);
+            return;
+        };
+
+        pool.scope(|scope| {
+            let mut rest = bytes;
+            let mut band_top = 0;
+            for first_row in (0..r_h).step_by(rows_per_band) {
+                let last_row = (first_row + rows_per_band).min(r_h);
+                // Bands end where the next cell row starts, the last one takes the remaining rows
+                let (band, band_bottom) = if last_row < r_h {
+                    let band_bottom = self.render_data.y[last_row] >> shift.1;
+                    let (band, tail) = std::mem::take(&mut rest).split_at_mut((band_bottom - band_top) * stride);
+                    rest = tail;
+                    (band, band_bottom)
+                } else {
+                    (std::mem::take(&mut rest), band_top)
+                };
+                let (blitter, top) = (&blitter, band_top);
+                scope.spawn(move |_| blitter.draw_rows(band, top, first_row..last_row));
+                band_top = band_bottom;
+            }
+        });
+    }
+}
+
+/// Read only state shared by the threads drawing the bands of one plane
+struct Blitter<'a> {
+    char_set: &'a CharSet,
+    render_data: &'a RenderData,
+    char_idx: &'a [usize],
+    cell_colors: &'a [[[u8; 3]; 2]],
+    plane: usize,
+    /// Chroma subsampling of the plane as powers of two
+    shift: (u32, u32),
+    stride: usize,
+    blend: bool,
+}
+impl Blitter<'_> {
+    /// Draws the cells of `rows` into `band`, whose first byte is on row `band_top` of the plane
+    fn draw_rows(&self, band: &mut [u8], band_top: usize, rows: Range<usize>) {
+        let r_w = self.render_data.r_w;
+        let (sx, sy) = self.shift;
+        let stride = self.stride;
+        let glyph_w = self.char_set.glyph_width() as usize;
+        let glyph_h = self.char_set.glyph_height() as usize;
+        for row in rows {
+            let y = self.render_data.y[row];
+            for (col, &x) in self.render_data.x.iter().enumerate() {
+                let cell = row*r_w + col;
+                let stamp = self.char_set.glyph(self.char_idx[cell]);
+
+                if !self.blend {
+                    let mut start = (y - band_top)*stride + x;
+                    for line in stamp.chunks_exact(glyph_w) {
+                        band[start..(start + glyph_w)].copy_from_slice(line);
+                        start += stride;
+                    }
+                    continue;
+                }
+
+                let [bg, fg] = self.cell_colors[cell];
+                let bg = bg[self.plane] as i32;
+                let diff = fg[self.plane] as i32 - bg;
+                for py in (y >> sy)..((y + glyph_h) >> sy) {
+                    let line = ((py << sy).max(y) - y).min(glyph_h - 1) * glyph_w;
+                    let start = (py - band_top)*stride;
+                    for px in (x >> sx)..((x + glyph_w) >> sx) {
+                        let coverage = stamp[line + ((px << sx).max(x) - x).min(glyph_w - 1)] as i32;
+                        band[start + px] = (bg + diff * coverage / 255) as u8;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Full range BT.601, the cell colors are taken from the scaled source as they are
+fn yuv_to_rgb([y, u, v]: [u8; 3]) -> [u8; 3] {
+    let (y, u, v) = (y as f32, u as f32 - 128., v as f32 - 128.);
+    [y + 1.402 * v, y - 0.344 * u - 0.714 * v, y + 1.772 * u].map(|c| c.round().clamp(0., 255.) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CharSetBuilder;
+
+    /// Subtitle rows of a `cols` wide grid after `text` was set, one string per row
+    fn subtitle(texts: &[&str], cols: u32, rows: u32) -> Vec<String> {
+        let char_set = CharSetBuilder::new(" #", 1)
+            .text_chars(" abcdefghijklmnopqrstuvwxyz")
+            .build_without_font()
+            .unwrap();
+        let options = RenderOptions { subtitle_rows: rows, render_threads: 1, ..Default::default() };
+        let mut renderer = FrameRenderer::new(char_set, RenderData::new(cols, rows + 1, cols, rows + 1), Pixel::GRAY8, options);
+        for text in texts {
+            renderer.set_subtitle(text);
+        }
+        renderer.subtitle_idx.chunks_exact(cols as usize)
+            .map(|row| row.iter().map(|&idx| renderer.char_set.char(idx)).collect())
+            .collect()
+    }
+
+    #[test]
+    fn subtitle_is_centered_at_the_bottom() {
+        assert_eq!(subtitle(&["hi"], 6, 2), ["      ", "  hi  "]);
+        assert_eq!(subtitle(&["one\ntwo"], 5, 3), ["     ", " one ", " two "]);
+    }
+
+    #[test]
+    fn subtitle_wraps_at_words() {
+        assert_eq!(subtitle(&["the quick brown fox"], 10, 2), ["the quick ", "brown fox "]);
+        assert_eq!(subtitle(&["ab cd"], 5, 1), ["ab cd"]);
+    }
+
+    #[test]
+    fn subtitle_breaks_words_longer_than_a_row() {
+        assert_eq!(subtitle(&["abcdefghijklmn"], 5, 3), ["abcde", "fghij", "klmn "]);
+        assert_eq!(subtitle(&["ab cdefghijk"], 5, 3), [" ab  ", "cdefg", "hijk "]);
+    }
+
+    #[test]
+    fn subtitle_keeps_the_first_rows_that_fit() {
+        assert_eq!(subtitle(&["ab cdefghijk"], 5, 2), [" ab  ", "cdefg"]);
+        assert_eq!(subtitle(&["one\ntwo\nthree"], 5, 1), [" one "]);
+    }
+
+    #[test]
+    fn subtitle_skips_missing_glyphs_and_clears() {
+        assert_eq!(subtitle(&["h\u{e9}llo W"], 5, 1), ["hllo "]);
+        assert_eq!(subtitle(&["hi", ""], 4, 1), ["    "]);
+    }
+}
@@ -0,0 +1,345 @@
+use std::{path::{Path, PathBuf}, str::FromStr};
+
+use ffmpeg_the_third::Rational;
+use ini::{Ini, Properties};
+
+use crate::{Error, TranscodeOptions};
+
+pub fn default_settings() -> Ini {
+    let mut ini = Ini::new();
+    ini.with_section(None::<String>)
+        .set("src", "src.mp4")
+        .set("src_frame_rate", "auto")
+        .set("dst", "dst.mkv")
+        .set("text_dst", "")
+        .set("html_dst", "")
+        .set("video_streams", "best")
+        .set("format", "auto")
+        .set("codec", "auto")
+        .set("pixel_format", "auto")
+        .set("palette_dither", "false")
+        .set("loop_count", "0")
+        .set("audio_codec", "auto")
+        .set("audio_bit_rate", "0")
+        .set("burn_subtitles", "false")
+        .set("subtitle_rows", "2")
+        .set("dst_h", "1080")
+        .set("render_h", "60")
+        .set("font_path", "./MonospaceTypewriter.ttf")
+        .set("char_set", "space.-^~:/*=+?%##&$$@@@@@@@@@@@@")
+        .set("font_thickness", "0.25")
+        .set("anti_aliasing", "false")
+        .set("supersampling", "1")
+        .set("auto_density", "false")
+        .set("density_gamma", "1.0")
+        .set("dither", "none")
+        .set("dither_stability", "0")
+        .set("brightness", "0")
+        .set("contrast", "1")
+        .set("gamma", "1")
+        .set("auto_levels", "off")
+        .set("auto_levels_smoothing", "0.9")
+        .set("auto_levels_clip", "0.005")
+        .set("color_mode", "gray")
+        .set("glyph_selection", "luminance")
+        .set("edge_operator", "sobel")
+        .set("edge_threshold", "40")
+        .set("edge_coherence", "0.5")
+        .set("edge_chars", "-|/\\_")
+        .set("cell_foreground", "contrast")
+        .set("cell_background", "source")
+        .set("render_threads", "0")
+        .set("decoder_threads", "0")
+        .set("encoder_threads", "0");
+    ini.with_section(Some("streams"))
+        .set("map", "")
+        .set("audio", "all")
+        .set("subtitle", "all")
+        .set("other", "all")
+        .set("commentary", "true");
+    ini
+}
+
+/// Reads the options of a transcode job from a settings file
+pub fn parse(settings: &Ini) -> Result<TranscodeOptions, Error> {
+    let base_settings = settings.general_section();
+    let src = get(base_settings, "src")?;
+    let dst = get(base_settings, "dst")?.trim();
+
+    // Relative paths are resolved against the optional roots, absolute paths are used as given
+    let output_dir = base_settings.get("output_dir");
+    let mut options = TranscodeOptions::new(
+        resolve_path(base_settings.get("input_dir"), src),
+        resolve_path(output_dir, dst),
+    );
+    if let Some(frame_rate) = base_settings.get("src_frame_rate").map(str::trim) {
+        if !frame_rate.is_empty() && frame_rate != "auto" {
+            options.src_frame_rate = Some(parse_frame_rate(frame_rate)?);
+        }
+    }
+    // Only text is written without a video
+    if dst.is_empty() || dst == "none" {
+        options.dst = None;
+    }
+    if let Some(text_dst) = base_settings.get("text_dst").map(str::trim).filter(|text_dst| !text_dst.is_empty()) {
+        options.text_dst = Some(resolve_path(output_dir, text_dst));
+    }
+    if let Some(html_dst) = base_settings.get("html_dst").map(str::trim).filter(|html_dst| !html_dst.is_empty()) {
+        options.html_dst = Some(resolve_path(output_dir, html_dst));
+    }
+    options.dst_h = parse_key(base_settings, "dst_h")?;
+    options.render_h = parse_key(base_settings, "render_h")?;
+    options.font_path = get(base_settings, "font_path")?.into();
+    options.char_set = get(base_settings, "char_set")?.replace("space", " ");
+    options.font_thickness = parse_key(base_settings, "font_thickness")?;
+    options.anti_aliased = parse_bool(base_settings, "anti_aliasing")?;
+    // A threshold of 1 leaves every glyph empty, a weight of 0 fills every cell
+    let thickness = options.font_thickness;
+    let (valid, range) = if options.anti_aliased {
+        (thickness > 0. && thickness.is_finite(), "greater than 0 with anti_aliasing")
+    } else {
+        (thickness > 0. && thickness < 1., "between 0 and 1")
+    };
+    if !valid {
+        return Err(Error::Config(format!("font_thickness must be {range}, got {thickness}")));
+    }
+    if base_settings.contains_key("supersampling") {
+        options.supersampling = parse_key(base_settings, "supersampling")?;
+    }
+    if parse_bool(base_settings, "auto_density")? {
+        options.density_gamma = Some(match base_settings.get("density_gamma") {
+            Some(_) => parse_key(base_settings, "density_gamma")?,
+            None => 1.,
+        });
+    }
+    if let Some(video_streams) = base_settings.get("video_streams") {
+        options.video_streams = video_streams.trim().parse()?;
+    }
+    if let Some(color_mode) = base_settings.get("color_mode") {
+        options.render.color_mode = color_mode.trim().parse()?;
+    }
+    if let Some(glyph_selection) = base_settings.get("glyph_selection") {
+        options.render.glyph_selection = glyph_selection.trim().parse()?;
+    }
+    if let Some(dither) = base_settings.get("dither") {
+        options.render.dither = dither.trim().parse()?;
+    }
+    if base_settings.contains_key("dither_stability") {
+        options.render.dither_stability = parse_key(base_settings, "dither_stability")?;
+    }
+    for (key, value) in [
+        ("brightness", &mut options.render.brightness),
+        ("contrast", &mut options.render.contrast),
+        ("gamma", &mut options.render.gamma),
+        ("auto_levels_smoothing", &mut options.render.auto_levels_smoothing),
+        ("auto_levels_clip", &mut options.render.auto_levels_clip),
+        ("edge_threshold", &mut options.render.edge_threshold),
+        ("edge_coherence", &mut options.render.edge_coherence),
+    ] {
+        if base_settings.contains_key(key) {
+            *value = parse_key(base_settings, key)?;
+        }
+    }
+    // A gamma of 0 turns every frame black, clipping half the pixels from both ends leaves nothing between them
+    let render = &options.render;
+    if let Some((key, value)) = [("brightness", render.brightness), ("contrast", render.contrast)].into_iter().find(|(_, value)| !value.is_finite()) {
+        return Err(Error::Config(format!("{key} must be a number, got {value}")));
+    }
+    if render.gamma <= 0. || !render.gamma.is_finite() {
+        return Err(Error::Config(format!("gamma must be greater than 0, got {}", render.gamma)));
+    }
+    if !(0. ..0.5).contains(&render.auto_levels_clip) {
+        return Err(Error::Config(format!("auto_levels_clip must be at least 0 and below 0.5, got {}", render.auto_levels_clip)));
+    }
+    if let Some(edge_operator) = base_settings.get("edge_operator") {
+        options.render.edge_operator = edge_operator.trim().parse()?;
+    }
+    if let Some(edge_chars) = base_settings.get("edge_chars") {
+        options.edge_chars = edge_chars.trim().to_owned();
+    }
+    if let Some(auto_levels) = base_settings.get("auto_levels") {
+        options.render.auto_levels = auto_levels.trim().parse()?;
+    }
+    if let Some(foreground) = base_settings.get("cell_foreground") {
+        options.render.cell_foreground = foreground.trim().parse()?;
+    }
+    if let Some(background) = base_settings.get("cell_background") {
+        options.render.cell_background = background.trim().parse()?;
+    }
+
+    if base_settings.contains_key("render_threads") {
+        options.render.render_threads = parse_key(base_settings, "render_threads")?;
+    }
+    if base_settings.contains_key("decoder_threads") {
+        options.decoder_threads = parse_key(base_settings, "decoder_threads")?;
+    }
+    if base_settings.contains_key("encoder_threads") {
+        options.encoder_threads = parse_key(base_settings, "encoder_threads")?;
+    }
+
+    if let Some(format) = base_settings.get("format").map(str::trim) {
+        options.format = (!format.is_empty() && format != "auto").then(|| format.to_owned());
+    }
+    if let Some(codec) = base_settings.get("codec").map(str::trim) {
+        options.codec = (!codec.is_empty() && codec != "auto").then(|| codec.to_owned());
+    }
+    if let Some(audio_codec) = base_settings.get("audio_codec").map(str::trim) {
+        options.audio_codec = (!audio_codec.is_empty() && audio_codec != "auto").then(|| audio_codec.to_owned());
+    }
+    if base_settings.contains_key("audio_bit_rate") {
+        options.audio_bit_rate = parse_key(base_settings, "audio_bit_rate")?;
+    }
+    // Rows are only reserved when subtitles are drawn in them
+    if parse_bool(base_settings, "burn_subtitles")? {
+        options.burn_subtitles = true;
+        options.render.subtitle_rows = match base_settings.get("subtitle_rows") {
+            Some(_) => parse_key(base_settings, "subtitle_rows")?,
+            None => 2,
+        };
+    }
+    if let Some(pixel_format) = base_settings.get("pixel_format").map(str::trim) {
+        if !pixel_format.is_empty() && pixel_format != "auto" {
+            options.pixel_format = Some(pixel_format.parse().map_err(|_| Error::Config(format!("Unknown pixel_format {pixel_format}")))?);
+        }
+    }
+    options.palette_dither = parse_bool(base_settings, "palette_dither")?;
+    if base_settings.contains_key("loop_count") {
+        options.loop_count = parse_key(base_settings, "loop_count")?;
+    }
+
+    // Comma separated input indices in map take precedence over the other rules
+    if let Some(streams) = settings.section(Some("streams")) {
+        if let Some(map) = streams.get("map").map(str::trim).filter(|map| !map.is_empty()) {
+            options.streams.map = Some(map
+                .split(',')
+                .map(|idx| idx.trim().parse().map_err(|_| Error::Config(format!("Invalid stream index in streams map: {idx}"))))
+                .collect::<Result<_, _>>()?);
+        }
+        for (key, filter) in [
+            ("audio", &mut options.streams.audio),
+            ("subtitle", &mut options.streams.subtitle),
+            ("other", &mut options.streams.other),
+        ] {
+            if let Some(value) = streams.get(key) {
+                *filter = value.trim().parse()?;
+            }
+        }
+        if streams.contains_key("commentary") {
+            options.streams.commentary = parse_bool(streams, "commentary")?;
+        }
+    }
+
+    // [libx264_options] is read for older settings files, [encoder_options] takes precedence over it
+    let sections = [settings.section(Some("libx264_options")), settings.section(Some("encoder_options"))];
+    if sections.iter().any(Option::is_some) {
+        options.encoder_options = sections
+            .into_iter()
+            .flatten()
+            .flat_map(|section| section.iter())
+            .map(|(key, val)| (key.to_owned(), val.to_owned()))
+            .collect();
+    }
+    Ok(options)
+}
+
+fn resolve_path(root: Option<&str>, path: &str) -> PathBuf {
+    match root {
+        Some(root) if !root.is_empty() => Path::new(root).join(path),
+        _ => PathBuf::from(path),
+    }
+}
+
+fn get<'a>(section: &'a Properties, key: &str) -> Result<&'a str, Error> {
+    section.get(key).ok_or_else(|| Error::Config(format!("No {key} specified")))
+}
+
+fn parse_key<T: FromStr>(section: &Properties, key: &str) -> Result<T, Error> {
+    let val = get(section, key)?;
+    val.trim().parse().map_err(|_| Error::Config(format!("Invalid value for {key}: {val}")))
+}
+
+/// Frame rate as a number of frames per second like `24` or `29.97`, or a fraction like `30000/1001`
+fn parse_frame_rate(val: &str) -> Result<Rational, Error> {
+    let invalid = || Error::Config(format!("Invalid value for src_frame_rate: {val}"));
+    let frame_rate = match val.split_once('/') {
+        Some((num, den)) => Rational(num.trim().parse().map_err(|_| invalid())?, den.trim().parse().map_err(|_| invalid())?),
+        None => {
+            let fps: f64 = val.parse().map_err(|_| invalid())?;
+            // Decimal rates like 29.97 are kept to the thousandth
+            Rational((fps * 1000.).round() as i32, 1000).reduce()
+        }
+    };
+    if frame_rate.numerator() <= 0 || frame_rate.denominator() <= 0 {
+        return Err(invalid());
+    }
+    Ok(frame_rate)
+}
+
+/// Missing keys are false
+fn parse_bool(section: &Properties, key: &str) -> Result<bool, Error> {
+    match section.get(key).map(str::trim) {
+        None | Some("false") | Some("0") | Some("no") => Ok(false),
+        Some("true") | Some("1") | Some("yes") => Ok(true),
+        Some(val) => Err(Error::Config(format!("Invalid value for {key}: {val}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_with(section: Option<&str>, key: &str, value: &str) -> Result<TranscodeOptions, Error> {
+        let mut settings = default_settings();
+        settings.set_to(section, key.into(), value.into());
+        parse(&settings)
+    }
+
+    #[test]
+    fn streams_map() {
+        let options = parse_with(Some("streams"), "map", "0, 2,5").unwrap();
+        assert_eq!(options.streams.map, Some(vec![0, 2, 5]));
+        assert_eq!(parse_with(Some("streams"), "map", "").unwrap().streams.map, None);
+    }
+
+    #[test]
+    fn streams_map_rejects_invalid_indices() {
+        assert!(parse_with(Some("streams"), "map", "0,,1").is_err());
+        assert!(parse_with(Some("streams"), "map", "-1").is_err());
+        assert!(parse_with(Some("streams"), "map", "a").is_err());
+    }
+
+    #[test]
+    fn frame_rate_fractions_and_decimals() {
+        assert_eq!(parse_frame_rate("30000/1001").unwrap(), Rational(30000, 1001));
+        assert_eq!(parse_frame_rate("24").unwrap(), Rational(24, 1));
+        assert_eq!(parse_frame_rate("29.97").unwrap(), Rational(2997, 100));
+        assert_eq!(parse_frame_rate(" 25 / 2 ").unwrap(), Rational(25, 2));
+    }
+
+    #[test]
+    fn frame_rate_rejects_zero_negative_and_garbage() {
+        for val in ["0", "0/1", "1/0", "-5", "-5/1", "5/-1", "fast", "24/", ""] {
+            assert!(parse_frame_rate(val).is_err(), "{val} was accepted");
+        }
+    }
+
+    #[test]
+    fn frame_rate_auto_is_unset() {
+        assert_eq!(parse_with(None, "src_frame_rate", "auto").unwrap().src_frame_rate, None);
+        assert_eq!(parse_with(None, "src_frame_rate", "12").unwrap().src_frame_rate, Some(Rational(12, 1)));
+    }
+
+    #[test]
+    fn levels_reject_out_of_range_values() {
+        for (key, val) in [
+            ("gamma", "0"), ("gamma", "-1"), ("gamma", "NaN"), ("gamma", "inf"),
+            ("contrast", "inf"), ("brightness", "NaN"),
+            ("auto_levels_clip", "0.5"), ("auto_levels_clip", "-0.1"), ("auto_levels_clip", "NaN"),
+        ] {
+            assert!(parse_with(None, key, val).is_err(), "{key}={val} was accepted");
+        }
+        assert_eq!(parse_with(None, "gamma", "0.4").unwrap().render.gamma, 0.4);
+        assert_eq!(parse_with(None, "contrast", "-1").unwrap().render.contrast, -1.);
+        assert_eq!(parse_with(None, "auto_levels_clip", "0").unwrap().render.auto_levels_clip, 0.);
+    }
+}
@@ -0,0 +1,152 @@
+use std::str::FromStr;
+
+use ffmpeg_the_third::{format::stream::{Disposition, StreamMut}, media, Rational, Stream};
+
+use crate::Error;
+
+/// Which streams of one type are kept. Parsed from `all`, `none`, or a comma separated list
+/// of language codes and `forced`, e.g. `eng,jpn` or `forced`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum TrackFilter {
+    #[default]
+    All,
+    None,
+    /// Streams tagged with one of `languages`, or of any language when it is empty.
+    /// With `forced` only streams marked as forced are kept.
+    Matching { languages: Vec<String>, forced: bool },
+}
+impl TrackFilter {
+    fn matches(&self, stream: &Stream) -> bool {
+        match self {
+            TrackFilter::All => true,
+            TrackFilter::None => false,
+            TrackFilter::Matching { languages, forced } => {
+                let metadata = stream.metadata();
+                let language = metadata.get("language").unwrap_or("und");
+                (languages.is_empty() || languages.iter().any(|l| l.eq_ignore_ascii_case(language)))
+                    && (!forced || stream.disposition().contains(Disposition::FORCED))
+            }
+        }
+    }
+}
+impl FromStr for TrackFilter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" | "all" => Ok(TrackFilter::All),
+            "none" => Ok(TrackFilter::None),
+            _ => {
+                let (mut languages, mut forced) = (Vec::new(), false);
+                for token in s.split(',').map(str::trim).filter(|token| !token.is_empty()) {
+                    match token {
+                        "forced" => forced = true,
+                        language if language.chars().all(|c| c.is_ascii_alphabetic()) => languages.push(language.to_owned()),
+                        _ => return Err(Error::Config(format!("Unknown stream filter {token}, expected all, none, forced or a language code"))),
+                    }
+                }
+                Ok(TrackFilter::Matching { languages, forced })
+            }
+        }
+    }
+}
+
+/// Picks the streams that are carried over to the output. Filtered video streams are always kept.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StreamSelection {
+    /// Indices of the input streams to keep, `None` applies the other rules instead
+    pub map: Option<Vec<usize>>,
+    pub audio: TrackFilter,
+    pub subtitle: TrackFilter,
+    /// Attachments such as fonts, and data streams
+    pub other: TrackFilter,
+    /// Keeps streams flagged as commentary or with commentary in their title
+    pub commentary: bool,
+}
+impl StreamSelection {
+    pub(crate) fn keeps(&self, stream: &Stream) -> bool {
+        if let Some(map) = &self.map {
+            return map.contains(&stream.index());
+        }
+        if !self.commentary && is_commentary(stream) {
+            return false;
+        }
+        match stream.parameters().medium() {
+            media::Type::Video => true,
+            media::Type::Audio => self.audio.matches(stream),
+            media::Type::Subtitle => self.subtitle.matches(stream),
+            _ => self.other.matches(stream),
+        }
+    }
+}
+impl Default for StreamSelection {
+    fn default() -> Self {
+        Self {
+            map: None,
+            audio: TrackFilter::All,
+            subtitle: TrackFilter::All,
+            other: TrackFilter::All,
+            commentary: true,
+        }
+    }
+}
+
+fn is_commentary(stream: &Stream) -> bool {
+    stream.disposition().contains(Disposition::COMMENT)
+        || stream.metadata().get("title").is_some_and(|title| title.to_lowercase().contains("commentary"))
+}
+
+/// Converter writing one stream of the output, which only learns the time base of that stream
+/// once the muxer picked it when the header was written
+pub(crate) trait OutputStream {
+    fn out_time_base_mut(&mut self) -> &mut Rational;
+}
+
+/// Gives every converter the time base the muxer picked for its output stream.
+/// `converters` and `stream_mapping` are indexed by input stream, `out_stream_tbs` by output stream.
+pub(crate) fn set_out_time_bases<T: OutputStream>(converters: &mut [Option<T>], stream_mapping: &[isize], out_stream_tbs: &[Rational]) {
+    for (converter, &out_stream_idx) in converters.iter_mut().zip(stream_mapping) {
+        if let (Some(converter), Ok(out_stream_idx)) = (converter, usize::try_from(out_stream_idx)) {
+            *converter.out_time_base_mut() = out_stream_tbs[out_stream_idx];
+        }
+    }
+}
+
+/// Carries the metadata of `in_stream`, including its language, and its dispositions over to `out_stream`
+pub(crate) fn copy_stream_tags(in_stream: &Stream, out_stream: &mut StreamMut) {
+    out_stream.set_metadata(in_stream.metadata().to_owned());
+    unsafe {
+        (*out_stream.as_mut_ptr()).disposition = in_stream.disposition().bits();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn track_filter_keywords() {
+        assert_eq!("".parse::<TrackFilter>().unwrap(), TrackFilter::All);
+        assert_eq!("all".parse::<TrackFilter>().unwrap(), TrackFilter::All);
+        assert_eq!("none".parse::<TrackFilter>().unwrap(), TrackFilter::None);
+    }
+
+    #[test]
+    fn track_filter_languages_and_forced() {
+        assert_eq!(
+            "eng, jpn".parse::<TrackFilter>().unwrap(),
+            TrackFilter::Matching { languages: vec!["eng".into(), "jpn".into()], forced: false },
+        );
+        assert_eq!("forced".parse::<TrackFilter>().unwrap(), TrackFilter::Matching { languages: Vec::new(), forced: true });
+        assert_eq!(
+            "eng,forced,".parse::<TrackFilter>().unwrap(),
+            TrackFilter::Matching { languages: vec!["eng".into()], forced: true },
+        );
+    }
+
+    #[test]
+    fn track_filter_rejects_unknown_tokens() {
+        assert!("eng,2".parse::<TrackFilter>().is_err());
+        assert!("en-US".parse::<TrackFilter>().is_err());
+    }
+}
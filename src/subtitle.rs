@@ -0,0 +1,224 @@
+use std::ptr;
+
+use ffmpeg_the_third::{codec::{self, subtitle::{Rect, Subtitle}, Parameters}, decoder, encoder, ffi::{av_mallocz, avcodec_encode_subtitle, AV_TIME_BASE}, format, util::error, Codec, Dictionary, Packet, Rational, Rescale, Stream};
+
+use crate::{streams::{copy_stream_tags, OutputStream}, Error};
+
+/// Largest encoded subtitle event
+const MAX_EVENT_SIZE: usize = 1 << 16;
+
+/// Whether subtitles in `id` are text that can be converted and drawn, rather than bitmaps
+pub(crate) fn is_text(id: codec::Id) -> bool {
+    matches!(
+        id,
+        codec::Id::SUBRIP | codec::Id::SRT | codec::Id::ASS | codec::Id::SSA | codec::Id::MOV_TEXT | codec::Id::WEBVTT | codec::Id::TEXT
+    )
+}
+
+/// Opens a text subtitle decoder that times its events with the packets of `in_stream`
+fn open_decoder(in_stream: &Stream) -> Result<decoder::Subtitle, Error> {
+    let mut decoder_ctx = codec::Context::from_parameters(in_stream.parameters())?;
+    unsafe {
+        (*decoder_ctx.as_mut_ptr()).pkt_timebase = in_stream.time_base().into();
+    }
+    Ok(decoder_ctx.decoder().subtitle()?)
+}
+
+/// Re-encodes a text subtitle stream into a codec the output container accepts, e.g. SRT into mov_text for mp4
+pub(crate) struct SubtitleTranscoder {
+    in_stream_tb: Rational,
+    decoder: decoder::Subtitle,
+    encoder: encoder::subtitle::Encoder,
+    out_stream_idx: usize,
+    out_stream_tb: Rational,
+    buffer: Vec<u8>,
+}
+impl SubtitleTranscoder {
+    /// Adds the output stream to `out_ctx`.
+    /// The stream is only added once the encoder is open, so a failure leaves `out_ctx` untouched.
+    pub(crate) fn new(in_stream: &Stream, codec: Codec, out_ctx: &mut format::context::Output) -> Result<Self, Error> {
+        let decoder = open_decoder(in_stream)?;
+
+        let global_header = out_ctx.format().flags().contains(format::Flags::GLOBAL_HEADER);
+        let mut encoder = codec::Context::new_with_codec(codec)
+            .encoder().subtitle()?;
+        encoder.set_time_base(Rational(1, AV_TIME_BASE));
+        if global_header {
+            encoder.set_flags(codec::Flags::GLOBAL_HEADER);
+        }
+        // Styles of the source are carried over through the ASS header every text decoder produces
+        unsafe {
+            let header = (*decoder.as_ptr()).subtitle_header;
+            let size = (*decoder.as_ptr()).subtitle_header_size;
+            if !header.is_null() && size > 0 {
+                let copy = av_mallocz(size as usize + 1) as *mut u8;
+                if copy.is_null() {
+                    return Err(ffmpeg_the_third::Error::Other { errno: error::ENOMEM }.into());
+                }
+                ptr::copy_nonoverlapping(header, copy, size as usize);
+                (*encoder.as_mut_ptr()).subtitle_header = copy;
+                (*encoder.as_mut_ptr()).subtitle_header_size = size;
+            }
+        }
+        let encoder = encoder.open_with(Dictionary::new())?;
+
+        let mut out_stream = out_ctx.add_stream(codec)?;
+        let out_stream_idx = out_stream.index();
+        out_stream.set_time_base(in_stream.time_base());
+        out_stream.set_parameters(Parameters::from(&encoder));
+        copy_stream_tags(in_stream, &mut out_stream);
+
+        Ok(Self {
+            in_stream_tb: in_stream.time_base(),
+            decoder,
+            encoder,
+            out_stream_idx,
+            out_stream_tb: in_stream.time_base(),
+            buffer: vec![0; MAX_EVENT_SIZE],
+        })
+    }
+
+    /// Every packet of a text stream holds one event, so the packet keeps its timing and only its payload changes
+    pub(crate) fn send_packet(&mut self, packet: Packet, out_ctx: &mut format::context::Output) -> Result<(), Error> {
+        let mut subtitle = Subtitle::new();
+        if !self.decoder.decode(&packet, &mut subtitle)? || subtitle.rects().count() == 0 {
+            return Ok(());
+        }
+        let duration = match packet.duration() {
+            0 => subtitle.end().saturating_sub(subtitle.start()) as i64,
+            duration => duration.rescale(self.in_stream_tb, Rational(1, 1000)),
+        };
+        subtitle.set_start(0);
+        subtitle.set_end(duration as u32);
+
+        let size = unsafe {
+            avcodec_encode_subtitle(self.encoder.as_mut_ptr(), self.buffer.as_mut_ptr(), self.buffer.len() as _, subtitle.as_ptr())
+        };
+        if size < 0 {
+            return Err(ffmpeg_the_third::Error::from(size).into());
+        }
+        let mut encoded = Packet::copy(&self.buffer[..size as usize]);
+        encoded.set_pts(packet.pts());
+        encoded.set_dts(packet.pts());
+        encoded.set_duration(duration.rescale(Rational(1, 1000), self.in_stream_tb));
+        encoded.rescale_ts(self.in_stream_tb, self.out_stream_tb);
+        encoded.set_stream(self.out_stream_idx);
+        encoded.write_interleaved(out_ctx)?;
+        Ok(())
+    }
+}
+
+impl OutputStream for SubtitleTranscoder {
+    fn out_time_base_mut(&mut self) -> &mut Rational {
+        &mut self.out_stream_tb
+    }
+}
+
+/// Text shown on screen from `start` until `end`, in microseconds
+#[derive(Clone, Debug)]
+pub(crate) struct Caption {
+    pub(crate) start: i64,
+    pub(crate) end: i64,
+    pub(crate) text: String,
+}
+
+/// Decodes a text subtitle stream into captions
+pub(crate) struct CaptionDecoder {
+    in_stream_tb: Rational,
+    decoder: decoder::Subtitle,
+}
+impl CaptionDecoder {
+    pub(crate) fn new(in_stream: &Stream) -> Result<Self, Error> {
+        Ok(Self {
+            in_stream_tb: in_stream.time_base(),
+            decoder: open_decoder(in_stream)?,
+        })
+    }
+
+    /// Events without a duration are shown until the next one, `end` is `i64::MAX` for them
+    pub(crate) fn decode(&mut self, packet: &Packet) -> Result<Option<Caption>, Error> {
+        let Some(pts) = packet.pts() else {
+            return Ok(None);
+        };
+        let mut subtitle = Subtitle::new();
+        if !self.decoder.decode(packet, &mut subtitle)? {
+            return Ok(None);
+        }
+
+        let (ms, us) = (Rational(1, 1000), Rational(1, AV_TIME_BASE));
+        let pts = pts.rescale(self.in_stream_tb, us);
+        let start = pts + (subtitle.start() as i64).rescale(ms, us);
+        let end = if subtitle.end() > subtitle.start() {
+            pts + (subtitle.end() as i64).rescale(ms, us)
+        } else if packet.duration() > 0 {
+            pts + packet.duration().rescale(self.in_stream_tb, us)
+        } else {
+            i64::MAX
+        };
+
+        let lines: Vec<String> = subtitle.rects()
+            .filter_map(|rect| match rect {
+                Rect::Text(text) => Some(text.get().to_owned()),
+                Rect::Ass(ass) => Some(ass_text(ass.get())),
+                _ => None,
+            })
+            .collect();
+        Ok(Some(Caption { start, end, text: lines.join("\n") }))
+    }
+}
+
+/// Plain text of an ASS dialogue event, without its fields and override tags
+fn ass_text(event: &str) -> String {
+    // ReadOrder, Layer, Style, Name, MarginL, MarginR, MarginV and Effect come before the text
+    let text = event.splitn(9, ',').nth(8).unwrap_or_default();
+    let mut plain = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => {
+                for c in chars.by_ref() {
+                    if c == '}' {
+                        break;
+                    }
+                }
+            }
+            '\\' => match chars.peek() {
+                Some('N' | 'n') => {
+                    chars.next();
+                    plain.push('\n');
+                }
+                Some('h') => {
+                    chars.next();
+                    plain.push(' ');
+                }
+                _ => plain.push(c),
+            },
+            c => plain.push(c),
+        }
+    }
+    plain
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ass_text_skips_fields_and_keeps_commas() {
+        assert_eq!(ass_text("0,0,Default,,0,0,0,,Hello"), "Hello");
+        assert_eq!(ass_text("3,1,Sign,Narrator,10,10,20,Fade,Wait, what?"), "Wait, what?");
+        assert_eq!(ass_text("0,0,Default"), "");
+    }
+
+    #[test]
+    fn ass_text_strips_override_tags() {
+        assert_eq!(ass_text(r"0,0,Default,,0,0,0,,{\i1}Hello{\i0} {\pos(10,20)\b1}world"), "Hello world");
+        assert_eq!(ass_text(r"0,0,Default,,0,0,0,,Cut {\b1 off"), "Cut ");
+    }
+
+    #[test]
+    fn ass_text_line_breaks_and_hard_spaces() {
+        assert_eq!(ass_text(r"0,0,Default,,0,0,0,,One\NTwo\nThree\hFour"), "One\nTwo\nThree Four");
+        assert_eq!(ass_text(r"0,0,Default,,0,0,0,,C:\path\"), r"C:\path\");
+    }
+}
@@ -0,0 +1,122 @@
+use std::{fs::File, io::{BufWriter, Write}, path::{Path, PathBuf}};
+
+use crate::{Error, FrameRenderer};
+
+/// Writes the character grid of every rendered frame as UTF-8 text
+pub(crate) struct TextWriter {
+    target: Target,
+    /// Number of the next frame, counting from 1
+    frame: u64,
+    text: String,
+}
+
+enum Target {
+    /// One file per frame, named by a pattern like `frame_%05d.txt`
+    Frames(String),
+    /// Every frame in one file, each after a separator line with its number and timestamp
+    Single(BufWriter<File>),
+}
+
+impl TextWriter {
+    pub(crate) fn create(path: &Path) -> Result<Self, Error> {
+        let pattern = path.to_string_lossy();
+        let target = if frame_path(&pattern, 1).is_some() {
+            Target::Frames(pattern.into_owned())
+        } else {
+            Target::Single(BufWriter::new(File::create(path)?))
+        };
+        Ok(Self { target, frame: 1, text: String::new() })
+    }
+
+    /// Writes the characters the renderer chose for its most recent frame, shown at `millis`
+    pub(crate) fn write(&mut self, renderer: &FrameRenderer, millis: Option<i64>) -> Result<(), Error> {
+        self.text.clear();
+        renderer.write_text(&mut self.text);
+        match &mut self.target {
+            Target::Frames(pattern) => {
+                let path = frame_path(pattern, self.frame).expect("Frame patterns have a number");
+                std::fs::write(path, &self.text)?;
+            }
+            Target::Single(file) => {
+                writeln!(file, "--- frame {} {} ---", self.frame, timestamp(millis))?;
+                file.write_all(self.text.as_bytes())?;
+            }
+        }
+        self.frame += 1;
+        Ok(())
+    }
+
+    pub(crate) fn finish(self) -> Result<(), Error> {
+        if let Target::Single(mut file) = self.target {
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// `hh:mm:ss.mmm`, or `--:--:--.---` for frames without a timestamp
+fn timestamp(millis: Option<i64>) -> String {
+    match millis {
+        Some(ms) => {
+            let ms = ms.max(0);
+            format!("{:02}:{:02}:{:02}.{:03}", ms / 3_600_000, ms / 60_000 % 60, ms / 1000 % 60, ms % 1000)
+        }
+        None => "--:--:--.---".into(),
+    }
+}
+
+/// Replaces the `%d` or `%0Nd` of `pattern` with `frame`, `None` when it has no such placeholder.
+/// `%%` stands for a literal percent sign.
+pub(crate) fn frame_path(pattern: &str, frame: u64) -> Option<PathBuf> {
+    let mut path = String::with_capacity(pattern.len() + 8);
+    let mut found = false;
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            path.push(c);
+            continue;
+        }
+        let mut width = String::new();
+        while let Some(&digit) = chars.peek().filter(|c| c.is_ascii_digit()) {
+            width.push(digit);
+            chars.next();
+        }
+        match chars.next() {
+            Some('%') if width.is_empty() => path.push('%'),
+            Some('d') => {
+                path.push_str(&format!("{frame:0width$}", width = width.parse().unwrap_or(0)));
+                found = true;
+            }
+            other => {
+                path.push('%');
+                path.push_str(&width);
+                path.extend(other);
+            }
+        }
+    }
+    found.then(|| path.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_path_zero_padded() {
+        assert_eq!(frame_path("frame_%05d.txt", 42), Some("frame_00042.txt".into()));
+        assert_eq!(frame_path("frame_%05d.txt", 1234567), Some("frame_1234567.txt".into()));
+    }
+
+    #[test]
+    fn frame_path_unpadded_and_escaped() {
+        assert_eq!(frame_path("%d.txt", 7), Some("7.txt".into()));
+        assert_eq!(frame_path("100%%/%d.txt", 3), Some("100%/3.txt".into()));
+    }
+
+    #[test]
+    fn frame_path_without_placeholder() {
+        assert_eq!(frame_path("frames.txt", 1), None);
+        assert_eq!(frame_path("100%%.txt", 1), None);
+        assert_eq!(frame_path("50%s_%x.txt", 1), None);
+    }
+}
@@ -0,0 +1,1084 @@
+use std::{ffi::{c_int, c_void, CString}, path::{Path, PathBuf}, ptr, str::FromStr, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, mpsc::{channel, sync_channel, Receiver, Sender, SyncSender}, Arc}, thread, time::{Duration, Instant}};
+
+use ffmpeg_the_third::{codec::{self, threading, Parameters}, decoder, encoder, ffi::{av_opt_find, avcodec_get_class, avformat_query_codec, AVClass, AV_OPT_SEARCH_FAKE_OBJ, AV_TIME_BASE, FF_COMPLIANCE_NORMAL}, format::{self, stream::Disposition, Pixel}, frame, media, software::scaling::{Context, Flags}, Codec, Dictionary, Packet, Rational, Rescale, Stream};
+
+use crate::{audio::AudioTranscoder, levels, streams::{copy_stream_tags, set_out_time_bases}, subtitle::{self, Caption, CaptionDecoder, SubtitleTranscoder}, html::HtmlWriter, palette::Quantizer, text::{self, TextWriter}, AutoLevels, CharSet, CharSetBuilder, ColorMode, Error, FrameRenderer, GlyphSelection, RenderData, RenderInput, RenderOptions, StreamSelection};
+
+/// Frames buffered between two pipeline stages
+const PIPELINE_DEPTH: usize = 8;
+/// Quality of encoders that have a crf option, when `encoder_options` doesn't set one
+const DEFAULT_CRF: &str = "24";
+
+/// Everything needed to filter one video
+#[derive(Clone, Debug)]
+pub struct TranscodeOptions {
+    /// Video, still image, or image sequence with a frame number placeholder like `frame_%05d.png`
+    pub src: PathBuf,
+    /// Frame rate an image sequence is read at, `None` leaves FFmpeg's default of 25
+    pub src_frame_rate: Option<Rational>,
+    /// Filtered video, `None` skips encoding when only `text_dst` or `html_dst` is wanted
+    pub dst: Option<PathBuf>,
+    /// Characters of every frame as UTF-8 text. A path with a frame number placeholder like `frame_%05d.txt`
+    /// writes one file per frame, any other path writes every frame to one file after a separator line
+    /// with its number and timestamp. Needs a single filtered video stream.
+    pub text_dst: Option<PathBuf>,
+    /// Single HTML page that plays the characters back as selectable text, with the cell colors unless the
+    /// color mode is [`ColorMode::Gray`]. Needs a single filtered video stream.
+    pub html_dst: Option<PathBuf>,
+    /// Height of the picture in pixels, burned subtitle rows make the output video taller
+    pub dst_h: u32,
+    /// Number of character rows of the picture, burned subtitle rows are added below them
+    pub render_h: u32,
+    /// ttf or otf font used to draw the characters
+    pub font_path: PathBuf,
+    /// Characters ordered from darkest to brightest
+    pub char_set: String,
+    /// Glyph coverage threshold above 0 and below 1, or the stroke weight above 0 when anti-aliased
+    pub font_thickness: f32,
+    /// Keeps glyph coverage as 0-255 values instead of thresholding it
+    pub anti_aliased: bool,
+    /// Glyphs are rasterized at this multiple of their size and averaged back down
+    pub supersampling: u32,
+    /// Sorts `char_set` by measured ink coverage with this gamma instead of using it as ordered
+    pub density_gamma: Option<f32>,
+    /// Horizontal, vertical, rising and falling edge glyphs, optionally followed by one for horizontal edges
+    /// at the bottom of a cell. Only used with [`GlyphSelection::Edge`].
+    pub edge_chars: String,
+    /// FFmpeg muxer name, e.g. `mp4`, `matroska`, `webm` or `nut`.
+    /// `None` guesses it from the extension of `dst`, so it is needed for extensionless or piped outputs.
+    pub format: Option<String>,
+    /// Name of the FFmpeg encoder, e.g. `libx265`, `libvpx-vp9` or `ffv1`.
+    /// `None` uses the default video codec of the output container.
+    pub codec: Option<String>,
+    /// Pixel format the video is encoded in, e.g. `Pixel::YUV444P`, `Pixel::GRAY8` or `Pixel::YUV420P10LE`.
+    /// `None` uses YUV420P, or for encoders without it like GIF and APNG, GRAY8 in gray mode, PAL8 for GIF,
+    /// RGB24 or the first format the encoder lists.
+    pub pixel_format: Option<Pixel>,
+    /// Options of the encoder. Keys it doesn't have are reported and skipped.
+    /// Encoders with a crf option get a crf of 24 unless one is set here.
+    pub encoder_options: Vec<(String, String)>,
+    /// Diffuses the error of colors dropped from a PAL8 frame's palette, only matters for colored frames
+    /// with more than 256 colors
+    pub palette_dither: bool,
+    /// Times an animated GIF or APNG plays, 0 repeats it forever
+    pub loop_count: u32,
+    /// Encoder audio is converted with, e.g. `aac`, `libopus` or `flac`. Setting it converts every audio stream,
+    /// `None` only converts the ones the container can't hold, to its default audio codec.
+    pub audio_codec: Option<String>,
+    /// Bit rate of converted audio in bits per second, 0 keeps the encoder default
+    pub audio_bit_rate: usize,
+    /// Draws the best text subtitle stream into the [`RenderOptions::subtitle_rows`] instead of copying it
+    pub burn_subtitles: bool,
+    /// Threads FFmpeg decodes with, 0 lets it decide
+    pub decoder_threads: usize,
+    /// Threads FFmpeg encodes with, 0 lets it decide
+    pub encoder_threads: usize,
+    /// Video streams that are filtered, every other video stream is copied
+    pub video_streams: VideoStreams,
+    /// Streams carried over next to the filtered video
+    pub streams: StreamSelection,
+    pub render: RenderOptions,
+}
+impl TranscodeOptions {
+    pub fn new(src: impl Into<PathBuf>, dst: impl Into<PathBuf>) -> Self {
+        Self {
+            src: src.into(),
+            src_frame_rate: None,
+            dst: Some(dst.into()),
+            text_dst: None,
+            html_dst: None,
+            dst_h: 1080,
+            render_h: 60,
+            font_path: "./MonospaceTypewriter.ttf".into(),
+            char_set: " .-^~:/*=+?%##&$$@@@@@@@@@@@@".into(),
+            font_thickness: 0.25,
+            anti_aliased: false,
+            supersampling: 1,
+            density_gamma: None,
+            edge_chars: "-|/\\_".into(),
+            format: None,
+            codec: None,
+            pixel_format: None,
+            encoder_options: Vec::new(),
+            palette_dither: false,
+            loop_count: 0,
+            audio_codec: None,
+            audio_bit_rate: 0,
+            burn_subtitles: false,
+            decoder_threads: 0,
+            encoder_threads: 0,
+            video_streams: VideoStreams::Best,
+            streams: StreamSelection::default(),
+            render: RenderOptions::default(),
+        }
+    }
+}
+
+/// Which video streams of the source are filtered
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VideoStreams {
+    /// The one FFmpeg considers the main stream
+    #[default]
+    Best,
+    /// Every video stream, each with its own encoder
+    All,
+    /// The stream at this index of the source
+    Index(usize),
+}
+impl FromStr for VideoStreams {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "best" => Ok(VideoStreams::Best),
+            "all" => Ok(VideoStreams::All),
+            _ => s.parse().map(VideoStreams::Index)
+                .map_err(|_| Error::Config(format!("Unknown video_streams {s}, expected best, all or a stream index"))),
+        }
+    }
+}
+
+/// Snapshot of a running transcode job
+#[derive(Clone, Copy, Debug)]
+pub struct Progress {
+    /// Frames rendered so far
+    pub frames: u64,
+    /// Estimated from the container when the stream doesn't report it
+    pub total_frames: u64,
+    pub elapsed: Duration,
+}
+
+/// Stops a running [`TranscodeJob`] or [`PlayJob`](crate::PlayJob) from another thread
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A decoded frame scaled to render resolution, with the finer detail luma when it is needed
+pub(crate) struct ScaledFrame {
+    pub(crate) frame: frame::Video,
+    detail: Option<frame::Video>,
+}
+impl ScaledFrame {
+    /// Buffers the renderer reads, the chroma planes are left out in [`ColorMode::Gray`]
+    pub(crate) fn input(&self, color_mode: ColorMode) -> RenderInput<'_> {
+        let planes = if color_mode == ColorMode::Gray {1} else {3};
+        let plane = |i: usize| if i < planes {(self.frame.data(i), self.frame.stride(i))} else {(&[][..], 0)};
+        let [(y, y_stride), (u, u_stride), (v, v_stride)] = [0, 1, 2].map(plane);
+        RenderInput {
+            planes: [y, u, v],
+            strides: [y_stride, u_stride, v_stride],
+            detail: self.detail.as_ref().map(|detail| (detail.data(0), detail.stride(0))),
+        }
+    }
+}
+
+pub(crate) struct Decoder {
+    decoder: decoder::Video,
+    scaler: Context,
+    /// Scales to the finer grid the shapes of the glyphs are compared on
+    detail_scaler: Option<Context>,
+    in_frame: frame::Video,
+    scaled_format: Pixel,
+    render_size: (u32, u32),
+    detail_size: (u32, u32),
+}
+impl Decoder {
+    pub(crate) fn new(decoder: decoder::Video, renderer: &FrameRenderer) -> Result<Self, Error> {
+        let (render_w, render_h) = renderer.input_size();
+        let scaled_format = renderer.options().color_mode.scaled_format();
+        let scaler = Context::get(
+            decoder.format(),
+            decoder.width(), decoder.height(),
+            scaled_format,
+            render_w, render_h,
+            Flags::FAST_BILINEAR,
+        )?;
+
+        let (detail_w, detail_h) = renderer.detail_size();
+        let detail_scaler = if renderer.options().needs_detail() {
+            Some(Context::get(
+                decoder.format(),
+                decoder.width(), decoder.height(),
+                Pixel::GRAY8,
+                detail_w, detail_h,
+                Flags::AREA,
+            )?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            decoder,
+            scaler,
+            detail_scaler,
+            in_frame: frame::Video::empty(),
+            scaled_format,
+            render_size: (render_w, render_h),
+            detail_size: (detail_w, detail_h),
+        })
+    }
+
+    /// Sends every frame the decoder has ready to the render stage.
+    /// Returns false once the render stage has stopped.
+    fn decode_frames(&mut self, frames: &SyncSender<ScaledFrame>) -> Result<bool, Error> {
+        while self.decoder.receive_frame(&mut self.in_frame).is_ok() {
+            // Scale frame to render resolution
+            let mut scaled_frame = frame::Video::new(self.scaled_format, self.render_size.0, self.render_size.1);
+            self.scaler.run(&self.in_frame, &mut scaled_frame)?;
+            scaled_frame.set_pts(self.in_frame.timestamp());
+            let detail = match self.detail_scaler.as_mut() {
+                Some(detail_scaler) => {
+                    let mut detail_frame = frame::Video::new(Pixel::GRAY8, self.detail_size.0, self.detail_size.1);
+                    detail_scaler.run(&self.in_frame, &mut detail_frame)?;
+                    Some(detail_frame)
+                }
+                None => None,
+            };
+
+            if frames.send(ScaledFrame { frame: scaled_frame, detail }).is_err() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Decodes packets until the demuxer closes the channel, then flushes the decoder
+    pub(crate) fn run(mut self, packets: Receiver<Packet>, frames: SyncSender<ScaledFrame>) -> Result<(), Error> {
+        for packet in packets {
+            self.decoder.send_packet(&packet)?;
+            if !self.decode_frames(&frames)? {
+                return Ok(());
+            }
+        }
+        self.decoder.send_eof()?;
+        self.decode_frames(&frames)?;
+        Ok(())
+    }
+}
+
+/// Draws scaled frames as characters and hands copies of the output frame to the encoder,
+/// converted to its pixel format when the renderer can't draw that directly.
+/// Paletted formats are converted to RGB first and then quantized.
+/// Captions arrive from the demuxer ahead of the frames they are shown on.
+/// The chosen characters are also written to the files in `grids`.
+struct RenderStage {
+    renderer: FrameRenderer,
+    converter: Option<Context>,
+    quantizer: Option<Quantizer>,
+    in_vid_tb: Rational,
+    grids: GridWriters,
+}
+impl RenderStage {
+    /// Frames are only drawn when `rendered` leads to an encoder, `frame_ct` counts every frame that was rendered
+    fn run(mut self, frames: Receiver<ScaledFrame>, captions: Receiver<Caption>, rendered: Option<SyncSender<frame::Video>>, frame_ct: &AtomicU64) -> Result<(), Error> {
+        let Self { renderer, converter, quantizer, in_vid_tb, grids } = &mut self;
+        let mut pending: Vec<Caption> = Vec::new();
+        let mut shown = String::new();
+        for scaled in frames {
+            if let Some(pts) = scaled.frame.pts() {
+                let pts = pts.rescale(*in_vid_tb, Rational(1, AV_TIME_BASE));
+                for caption in captions.try_iter() {
+                    // Events without an end last until the next one starts
+                    for open in pending.iter_mut().filter(|open| open.end == i64::MAX) {
+                        open.end = caption.start;
+                    }
+                    pending.push(caption);
+                }
+                pending.retain(|caption| caption.end > pts);
+                let text: Vec<&str> = pending.iter()
+                    .filter(|caption| caption.start <= pts)
+                    .map(|caption| caption.text.as_str())
+                    .collect();
+                let text = text.join("\n");
+                if text != shown {
+                    renderer.set_subtitle(&text);
+                    shown = text;
+                }
+            }
+
+            let input = scaled.input(renderer.options().color_mode);
+            // Characters are only drawn into pixels for the encoder
+            let Some(rendered) = rendered.as_ref() else {
+                renderer.select(&input);
+                grids.write(renderer, &scaled.frame, *in_vid_tb)?;
+                frame_ct.fetch_add(1, Ordering::Relaxed);
+                continue;
+            };
+            let out_frame = renderer.render_input(&input);
+            out_frame.set_pts(scaled.frame.pts());
+            let out_frame = match converter.as_mut() {
+                Some(converter) => {
+                    let mut converted = frame::Video::empty();
+                    converter.run(out_frame, &mut converted)?;
+                    converted.set_pts(out_frame.pts());
+                    converted
+                }
+                None => out_frame.clone(),
+            };
+            let out_frame = match quantizer.as_mut() {
+                Some(quantizer) => quantizer.quantize(&out_frame),
+                None => out_frame,
+            };
+            grids.write(renderer, &scaled.frame, *in_vid_tb)?;
+            frame_ct.fetch_add(1, Ordering::Relaxed);
+            if rendered.send(out_frame).is_err() {
+                break;
+            }
+        }
+        self.grids.finish()
+    }
+}
+
+/// Files the chosen characters are written to besides the video
+struct GridWriters {
+    text: Option<TextWriter>,
+    html: Option<HtmlWriter>,
+}
+impl GridWriters {
+    fn write(&mut self, renderer: &FrameRenderer, scaled: &frame::Video, in_vid_tb: Rational) -> Result<(), Error> {
+        let millis = scaled.pts().map(|pts| pts.rescale(in_vid_tb, Rational(1, 1000)));
+        if let Some(text) = self.text.as_mut() {
+            text.write(renderer, millis)?;
+        }
+        if let Some(html) = self.html.as_mut() {
+            html.write(renderer, millis)?;
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), Error> {
+        if let Some(text) = self.text {
+            text.finish()?;
+        }
+        if let Some(html) = self.html {
+            html.finish()?;
+        }
+        Ok(())
+    }
+}
+
+/// Encodes rendered frames and passes the packets back to the muxer, flushing the encoder at the end
+fn encode_stream(mut encoder: encoder::Video, frames: Receiver<frame::Video>, packets: Sender<Packet>, out_vid_stream_idx: usize, in_vid_tb: Rational, out_vid_tb: Rational) -> Result<(), Error> {
+    for frame in frames {
+        encoder.send_frame(&frame)?;
+        encode_frames(&mut encoder, out_vid_stream_idx, in_vid_tb, out_vid_tb, &packets);
+    }
+    encoder.send_eof()?;
+    encode_frames(&mut encoder, out_vid_stream_idx, in_vid_tb, out_vid_tb, &packets);
+    Ok(())
+}
+
+/// Whether `stream` is video that can be filtered, attached pictures like cover art are not
+pub(crate) fn is_filterable(stream: &Stream) -> bool {
+    stream.parameters().medium() == media::Type::Video && !stream.disposition().contains(Disposition::ATTACHED_PIC)
+}
+
+/// The video stream FFmpeg considers the main one, unless that is an attached picture.
+/// Then the default stream with the most frames among the filterable ones is taken.
+pub(crate) fn best_video_stream(in_ctx: &format::context::Input) -> Option<Stream<'_>> {
+    in_ctx.streams().best(media::Type::Video)
+        .filter(is_filterable)
+        .or_else(|| in_ctx.streams()
+            .filter(is_filterable)
+            .min_by_key(|stream| (!stream.disposition().contains(Disposition::DEFAULT), std::cmp::Reverse(stream.frames()))))
+}
+
+/// Opens the source of `options`. Image sequences are recognized by FFmpeg from the placeholder in their name.
+pub(crate) fn open_input(options: &TranscodeOptions) -> Result<format::context::Input, Error> {
+    let mut input_opts = Dictionary::new();
+    if let Some(frame_rate) = options.src_frame_rate {
+        input_opts.set("framerate", &format!("{}/{}", frame_rate.numerator(), frame_rate.denominator()));
+    }
+    Ok(format::input_with_dictionary(&options.src, input_opts)?)
+}
+
+/// Decodes a whole video stream once at render resolution and measures its black and white points
+pub(crate) fn analyze_levels(options: &TranscodeOptions, in_vid_stream_idx: usize, width: u32, height: u32, clip: f32, cancel: &CancelToken) -> Result<(u8, u8), Error> {
+    let mut in_ctx = open_input(options)?;
+    let in_vid_stream = in_ctx.stream(in_vid_stream_idx)
+        .ok_or(Error::NoVideoStream)?;
+    let mut decoder = codec::Context::from_parameters(in_vid_stream.parameters())?
+        .decoder().video()?;
+    let mut scaler = Context::get(
+        decoder.format(),
+        decoder.width(), decoder.height(),
+        Pixel::GRAY8,
+        width, height,
+        Flags::FAST_BILINEAR,
+    )?;
+
+    let mut in_frame = frame::Video::empty();
+    let mut scaled_frame = frame::Video::new(Pixel::GRAY8, width, height);
+    let mut histogram = [0; 256];
+    let mut receive_frames = |decoder: &mut decoder::Video, histogram: &mut [u64; 256]| -> Result<(), Error> {
+        while decoder.receive_frame(&mut in_frame).is_ok() {
+            scaler.run(&in_frame, &mut scaled_frame)?;
+            levels::accumulate(histogram, scaled_frame.data(0), scaled_frame.stride(0), width as usize, height as usize);
+        }
+        Ok(())
+    };
+
+    for (stream, packet) in in_ctx.packets().filter_map(Result::ok) {
+        if cancel.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        if stream.index() == in_vid_stream_idx {
+            decoder.send_packet(&packet)?;
+            receive_frames(&mut decoder, &mut histogram)?;
+        }
+    }
+    decoder.send_eof()?;
+    receive_frames(&mut decoder, &mut histogram)?;
+    Ok(levels::histogram_range(&histogram, clip))
+}
+
+fn encode_frames(encoder: &mut encoder::Video, out_vid_stream_idx: usize, in_vid_tb: Rational, out_vid_tb: Rational, packets: &Sender<Packet>) {
+    let mut encoded = Packet::empty();
+    while encoder.receive_packet(&mut encoded).is_ok() {
+        encoded.rescale_ts(in_vid_tb, out_vid_tb);
+        encoded.set_stream(out_vid_stream_idx);
+        // The muxer only stops listening when it has failed itself
+        let _ = packets.send(std::mem::replace(&mut encoded, Packet::empty()));
+    }
+}
+
+/// Whether `codec` has an option named `key`, one of its own or one of every codec context
+fn has_option(codec: Codec, key: &str) -> bool {
+    let Ok(key) = CString::new(key) else {
+        return false;
+    };
+    let classes = unsafe {[avcodec_get_class(), (*codec.as_ptr()).priv_class]};
+    classes.into_iter().filter(|class| !class.is_null()).any(|class| {
+        // Classes are searched through a pointer to them standing in for an object
+        let fake_obj = &class as *const *const AVClass as *mut c_void;
+        unsafe {!av_opt_find(fake_obj, key.as_ptr(), ptr::null(), 0, AV_OPT_SEARCH_FAKE_OBJ as c_int).is_null()}
+    })
+}
+
+/// Whether the muxer of `out_ctx` can hold `id`. Muxers without a codec list can't tell, those are
+/// given the benefit of the doubt and `write_header` has the final word.
+fn can_hold(out_ctx: &format::context::Output, id: codec::Id) -> bool {
+    unsafe {avformat_query_codec(out_ctx.format().as_ptr(), id.into(), FF_COMPLIANCE_NORMAL)} != 0
+}
+
+/// Encoder every filtered stream is encoded with, and the pixel format it encodes in
+fn video_encoder(options: &TranscodeOptions, dst: &Path, out_ctx: &format::context::Output, log: &mut impl FnMut(String)) -> Result<(Codec, Pixel), Error> {
+    let codec = match &options.codec {
+        Some(name) => encoder::find_by_name(name)
+            .ok_or_else(|| Error::Config(format!("FFmpeg has no encoder named {name}")))?,
+        None => encoder::find(out_ctx.format().codec(dst, media::Type::Video))
+            .ok_or_else(|| Error::Config(format!("No encoder for the default video codec of {}", out_ctx.format().name())))?,
+    };
+    if codec.medium() != media::Type::Video {
+        return Err(Error::Config(format!("{} is not a video encoder", codec.name())));
+    }
+    if !can_hold(out_ctx, codec.id()) {
+        return Err(Error::Config(format!("{} can't hold {} video", out_ctx.format().name(), codec.name())));
+    }
+
+    // Typos and options of other encoders would otherwise be dropped without a word
+    for (key, _) in options.encoder_options.iter().filter(|(key, _)| !has_option(codec, key)) {
+        log(format!("Ignoring encoder option {key}, {} doesn't have it", codec.name()));
+    }
+
+    // Encoders that don't list their formats are trusted to accept any
+    let supported_fmts: Option<Vec<Pixel>> = codec.video()?.formats().map(|formats| formats.collect());
+    let dst_fmt = match (options.pixel_format, &supported_fmts) {
+        (Some(format), Some(supported)) if !supported.contains(&format) => {
+            let names: Vec<&str> = supported.iter().map(|&format| pixel_name(format)).collect();
+            return Err(Error::Config(format!(
+                "{} doesn't support the {} pixel format, use one of {}",
+                codec.name(), pixel_name(format), names.join(", "),
+            )));
+        }
+        (Some(format), _) => format,
+        (None, Some(supported)) if !supported.contains(&Pixel::YUV420P) => {
+            // GIF can change its palette every frame, APNG only has one so colors are kept as RGB
+            let gray = options.render.color_mode == ColorMode::Gray;
+            [(gray, Pixel::GRAY8), (codec.id() == codec::Id::GIF, Pixel::PAL8), (true, Pixel::RGB24)].into_iter()
+                .find(|&(wanted, format)| wanted && supported.contains(&format))
+                .map_or(supported[0], |(_, format)| format)
+        }
+        (None, _) => Pixel::YUV420P,
+    };
+    Ok((codec, dst_fmt))
+}
+
+/// Loop count of the animated image muxers, and whether single images are overwritten by every frame.
+/// Other muxers are left at their defaults.
+fn muxer_options(options: &TranscodeOptions, out_ctx: &format::context::Output) -> Dictionary<'static> {
+    let mut muxer_opts = Dictionary::new();
+    match out_ctx.format().name() {
+        // An image without a frame number keeps the last frame instead of failing on the second
+        "image2" if options.dst.as_ref().is_some_and(|dst| text::frame_path(&dst.to_string_lossy(), 0).is_none()) => {
+            muxer_opts.set("update", "1");
+        }
+        // The GIF muxer counts repeats after the first play, -1 plays it once
+        "gif" => muxer_opts.set("loop", &match options.loop_count {
+            0 => 0,
+            1 => -1,
+            plays => plays as i64 - 1,
+        }.to_string()),
+        "apng" => muxer_opts.set("plays", &options.loop_count.to_string()),
+        _ => {}
+    }
+    muxer_opts
+}
+
+/// Encoder for audio the output can't hold as it is, checked against the container
+fn audio_encoder(options: &TranscodeOptions, dst: &Path, out_ctx: &format::context::Output) -> Result<Codec, Error> {
+    let codec = match &options.audio_codec {
+        Some(name) => encoder::find_by_name(name)
+            .ok_or_else(|| Error::Config(format!("FFmpeg has no encoder named {name}")))?,
+        None => encoder::find(out_ctx.format().codec(dst, media::Type::Audio))
+            .ok_or_else(|| Error::Config(format!("No encoder for the default audio codec of {}", out_ctx.format().name())))?,
+    };
+    if codec.medium() != media::Type::Audio {
+        return Err(Error::Config(format!("{} is not an audio encoder", codec.name())));
+    }
+    if !can_hold(out_ctx, codec.id()) {
+        return Err(Error::Config(format!("{} can't hold {} audio", out_ctx.format().name(), codec.name())));
+    }
+    Ok(codec)
+}
+
+/// Encoder for text subtitles the output can't hold as they are, the default subtitle codec of the container
+fn subtitle_encoder(dst: &Path, out_ctx: &format::context::Output) -> Result<Codec, Error> {
+    let id = out_ctx.format().codec(dst, media::Type::Subtitle);
+    if id == codec::Id::None {
+        return Err(Error::Config(format!("{} has no subtitle codec", out_ctx.format().name())));
+    }
+    let codec = encoder::find(id)
+        .ok_or_else(|| Error::Config(format!("No encoder for the default subtitle codec of {}", out_ctx.format().name())))?;
+    if !subtitle::is_text(codec.id()) {
+        return Err(Error::Config(format!("{} subtitles are not text", codec.name())));
+    }
+    Ok(codec)
+}
+
+/// Printable ASCII and Latin-1, the characters burned in subtitles can be drawn with
+fn subtitle_chars() -> String {
+    (' '..='~').chain('¡'..='ÿ').collect()
+}
+
+/// FFmpeg's name of a pixel format, as accepted by the pixel_format setting
+fn pixel_name(format: Pixel) -> &'static str {
+    format.descriptor().map_or("unknown", |descriptor| descriptor.name())
+}
+
+/// Frame threading with `threads` threads, 0 lets FFmpeg pick the count
+pub(crate) fn threading_config(threads: usize) -> threading::Config {
+    let mut config = threading::Config::count(threads);
+    config.kind = threading::Type::Frame;
+    config
+}
+
+/// Width and height of the frames filtered from a `src_w` by `src_h` video, and their number of character columns
+fn frame_layout(options: &TranscodeOptions, src_w: u32, src_h: u32, char_set: &CharSet) -> (u32, u32, u32) {
+    let mut dst_h = options.dst_h;
+    let mut dst_w = dst_h * src_w / src_h;
+    dst_w -= dst_w % 2;
+    dst_h -= dst_h % 2;
+    let render_w = dst_w / char_set.glyph_width();
+    // Subtitle rows go below the picture at the same cell height, so it keeps its aspect ratio.
+    // Rounding up keeps every row at least as tall as a glyph.
+    let subtitle_rows = options.render.subtitle_rows;
+    dst_h += subtitle_rows * dst_h / options.render_h;
+    dst_h += dst_h % 2;
+    (dst_w, dst_h, render_w)
+}
+
+/// Decoder, renderer and encoder of one filtered video stream
+struct VideoPipeline {
+    in_stream_idx: usize,
+    decoder: Decoder,
+    render: RenderStage,
+    /// Encoder and the index of its output stream, `None` when only text is written
+    encoder: Option<(encoder::Video, usize)>,
+}
+impl VideoPipeline {
+    /// Adds the filtered stream to the muxer of `output`, encoded with its codec in its pixel format.
+    /// Without an output the frames are only written as text.
+    fn new(options: &TranscodeOptions, in_stream: &Stream, char_set: CharSet, output: Option<(&mut format::context::Output, Codec, Pixel)>) -> Result<Self, Error> {
+        let in_tb = in_stream.time_base();
+
+        // Creates decoder
+        let mut decoder_ctx = codec::Context::from_parameters(in_stream.parameters())?;
+        decoder_ctx.set_threading(threading_config(options.decoder_threads));
+        let decoder = decoder_ctx.decoder().video()?;
+
+        // Relevant data
+        let (dst_w, dst_h, render_w) = frame_layout(options, decoder.width(), decoder.height(), &char_set);
+        let render_h = options.render_h + options.render.subtitle_rows;
+
+        let (render_fmt, encoder, converter, quantizer) = match output {
+            Some((out_ctx, codec, dst_fmt)) => {
+                // Other formats are drawn in 4:4:4 and converted
+                let render_fmt = if FrameRenderer::supports(dst_fmt) {dst_fmt} else {Pixel::YUV444P};
+
+                // The time base is only a hint, the muxer settles on its own when the header is written
+                let global_header = out_ctx.format().flags().contains(format::Flags::GLOBAL_HEADER);
+                let mut out_stream = out_ctx.add_stream(codec)?;
+                out_stream.set_time_base(in_tb);
+                let out_stream_idx = out_stream.index();
+
+                // Creates encoder
+                let mut encoder = codec::context::Context::new_with_codec(codec)
+                    .encoder().video()?;
+                encoder.set_width(dst_w);
+                encoder.set_height(dst_h);
+                encoder.set_aspect_ratio(decoder.aspect_ratio());
+                encoder.set_format(dst_fmt);
+                encoder.set_frame_rate(Some(in_stream.avg_frame_rate()));
+                encoder.set_time_base(in_tb);
+                encoder.set_threading(threading_config(options.encoder_threads));
+
+                if global_header {
+                    encoder.set_flags(codec::Flags::GLOBAL_HEADER);
+                }
+
+                let mut encoder_opts = Dictionary::new();
+                if has_option(codec, "crf") && !options.encoder_options.iter().any(|(key, _)| key == "crf") {
+                    encoder_opts.set("crf", DEFAULT_CRF);
+                }
+                for (key, val) in options.encoder_options.iter().filter(|(key, _)| has_option(codec, key)) {
+                    encoder_opts.set(key, val);
+                }
+
+                let encoder = encoder.open_with(encoder_opts)?;
+                out_stream.set_parameters(Parameters::from(&encoder));
+                copy_stream_tags(in_stream, &mut out_stream);
+
+                let (convert_fmt, quantizer) = if dst_fmt == Pixel::PAL8 {
+                    (Pixel::RGB24, Some(Quantizer::new(options.palette_dither)))
+                } else {
+                    (dst_fmt, None)
+                };
+                let converter = if render_fmt != convert_fmt {
+                    Some(Context::get(render_fmt, dst_w, dst_h, convert_fmt, dst_w, dst_h, Flags::BILINEAR)?)
+                } else {
+                    None
+                };
+                (render_fmt, Some((encoder, out_stream_idx)), converter, quantizer)
+            }
+            // Frames are never drawn, the renderer only needs some format to hold them in
+            None => (Pixel::GRAY8, None, None, None),
+        };
+
+        // Create transcoding data structures
+        let render_data = RenderData::new(render_w, render_h, dst_w, dst_h);
+        let renderer = FrameRenderer::new(char_set, render_data, render_fmt, options.render.clone());
+        let decoder = Decoder::new(decoder, &renderer)?;
+        let grids = GridWriters {
+            text: options.text_dst.as_deref().map(TextWriter::create).transpose()?,
+            html: options.html_dst.as_deref().map(|path| HtmlWriter::create(path, options.render.color_mode)).transpose()?,
+        };
+
+        Ok(Self {
+            in_stream_idx: in_stream.index(),
+            decoder,
+            render: RenderStage { renderer, converter, quantizer, in_vid_tb: in_tb, grids },
+            encoder,
+        })
+    }
+}
+
+/// Frame count of a video stream, estimated from the container duration when the stream doesn't report it
+fn frame_count(stream: &Stream, duration: i64) -> u64 {
+    let mut total_frames = stream.frames();
+    if total_frames == 0 {
+        total_frames = duration
+        / AV_TIME_BASE as i64
+        * stream.avg_frame_rate().numerator() as i64
+        / stream.avg_frame_rate().denominator().max(1) as i64;
+    }
+    total_frames.max(0) as u64
+}
+
+/// Filters the selected video streams of `src` into `dst`, copying every other stream the output format accepts.
+/// The characters of the frames are written to `text_dst` and `html_dst` as well when they are set.
+pub struct TranscodeJob {
+    options: TranscodeOptions,
+    on_progress: Option<Box<dyn FnMut(Progress) + Send>>,
+    on_log: Option<Box<dyn FnMut(&str) + Send>>,
+    progress_interval: Duration,
+    cancel: CancelToken,
+}
+impl TranscodeJob {
+    pub fn new(options: TranscodeOptions) -> Self {
+        Self {
+            options,
+            on_progress: None,
+            on_log: None,
+            progress_interval: Duration::from_secs(5),
+            cancel: CancelToken::new(),
+        }
+    }
+
+    /// Called every `progress_interval` while packets are processed, and once more when the job is done
+    pub fn on_progress(mut self, callback: impl FnMut(Progress) + Send + 'static) -> Self {
+        self.on_progress = Some(Box::new(callback));
+        self
+    }
+
+    /// Called with a line about every stream that is dropped or converted, and every encoder option that is ignored.
+    /// Nothing is printed without it, as the output may be written to stdout.
+    pub fn on_log(mut self, callback: impl FnMut(&str) + Send + 'static) -> Self {
+        self.on_log = Some(Box::new(callback));
+        self
+    }
+
+    pub fn progress_interval(mut self, interval: Duration) -> Self {
+        self.progress_interval = interval;
+        self
+    }
+
+    /// Cancelling stops reading the input. The frames processed so far are still written to a valid file.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    pub fn run(mut self) -> Result<Progress, Error> {
+        let options = &self.options;
+        let start_t = Instant::now();
+        let mut last_t = Instant::now();
+        let mut on_log = self.on_log.take();
+        let mut log = |message: String| if let Some(on_log) = on_log.as_mut() {
+            on_log(&message);
+        };
+        ffmpeg_the_third::init()?;
+
+        // Input
+        let mut in_ctx = open_input(options)?;
+
+        // Finds the video streams to filter, attached pictures like cover art are only ever copied
+        let filtered: Vec<usize> = match options.video_streams {
+            VideoStreams::Best => vec![best_video_stream(&in_ctx).ok_or(Error::NoVideoStream)?.index()],
+            VideoStreams::All => in_ctx.streams().filter(is_filterable).map(|stream| stream.index()).collect(),
+            VideoStreams::Index(idx) => match in_ctx.stream(idx) {
+                Some(stream) if is_filterable(&stream) => vec![idx],
+                _ => return Err(Error::Config(format!("Stream {idx} of {} is not a video stream", options.src.display()))),
+            },
+        };
+        if filtered.is_empty() {
+            return Err(Error::NoVideoStream);
+        }
+        if options.dst.is_none() && options.text_dst.is_none() && options.html_dst.is_none() {
+            return Err(Error::Config("Nothing to write, set dst, text_dst or html_dst".into()));
+        }
+        // Every filtered stream would write to the same files
+        if (options.text_dst.is_some() || options.html_dst.is_some()) && filtered.len() > 1 {
+            return Err(Error::Config("text_dst and html_dst need a single filtered video stream".into()));
+        }
+
+        // Check inputs
+        let render_h = options.render_h;
+        if render_h == 0 {
+            return Err(Error::Config("render_h must be greater than 0".into()));
+        }
+        let subtitle_rows = options.render.subtitle_rows;
+        if options.burn_subtitles && subtitle_rows == 0 {
+            return Err(Error::Config("subtitle_rows must be at least 1 to burn subtitles".into()));
+        }
+
+        if let Some(idx) = options.streams.map.iter().flatten().find(|&&idx| idx >= in_ctx.nb_streams() as usize) {
+            return Err(Error::Config(format!("Stream {idx} is mapped but {} only has {} streams", options.src.display(), in_ctx.nb_streams())));
+        }
+
+        // Subtitle stream drawn on the frames, the best one unless the selection leaves it out
+        let burn_stream = if options.burn_subtitles {
+            let best = in_ctx.streams().best(media::Type::Subtitle).map(|stream| stream.index());
+            let stream = in_ctx.streams()
+                .filter(|stream| stream.parameters().medium() == media::Type::Subtitle && options.streams.keeps(stream))
+                .min_by_key(|stream| Some(stream.index()) != best)
+                .ok_or_else(|| Error::Config(format!("{} has no selected subtitles to burn", options.src.display())))?;
+            let id = stream.parameters().id();
+            if !subtitle::is_text(id) {
+                return Err(Error::Config(format!("Only text subtitles can be burned, stream {} is {}", stream.index(), id.name())));
+            }
+            Some(stream.index())
+        } else {
+            None
+        };
+
+        // Font, cells are the same height in every stream
+        let font_h = (options.dst_h - options.dst_h % 2) / render_h;
+        let mut char_set = CharSetBuilder::new(options.char_set.as_str(), font_h)
+            .font_thickness(options.font_thickness)
+            .anti_aliased(options.anti_aliased)
+            .supersampling(options.supersampling)
+            .align_baseline(options.render.needs_detail());
+        if let Some(gamma) = options.density_gamma {
+            char_set = char_set.auto_density(gamma);
+        }
+        if options.render.glyph_selection == GlyphSelection::Edge {
+            if !(4..=5).contains(&options.edge_chars.chars().count()) {
+                return Err(Error::Config("edge_chars must contain 4 or 5 characters".into()));
+            }
+            char_set = char_set.edge_chars(options.edge_chars.as_str());
+        }
+        if subtitle_rows > 0 {
+            char_set = char_set.text_chars(subtitle_chars());
+        }
+        let char_set = char_set.build_from_file(&options.font_path)?;
+
+        // Levels of the whole video are measured before the output is opened, so failing or cancelling leaves no file behind
+        let mut levels_ranges = vec![None; in_ctx.nb_streams() as _];
+        if options.render.auto_levels == AutoLevels::Video {
+            for &stream_idx in &filtered {
+                let stream = in_ctx.stream(stream_idx).ok_or(Error::NoVideoStream)?;
+                let decoder = codec::Context::from_parameters(stream.parameters())?.decoder().video()?;
+                let (_, _, render_w) = frame_layout(options, decoder.width(), decoder.height(), &char_set);
+                levels_ranges[stream_idx] = Some(analyze_levels(options, stream_idx, render_w, render_h, options.render.auto_levels_clip, &self.cancel)?);
+            }
+        }
+
+        // Output, its container is guessed from the extension unless it is given.
+        // Every filtered stream is encoded with the same codec.
+        let mut output = match &options.dst {
+            Some(dst) => {
+                let out_ctx = match &options.format {
+                    Some(name) => format::output_as(dst, name)
+                        .map_err(|e| Error::Config(format!("Couldn't open {} as {name}: {e}", dst.display())))?,
+                    None => format::output(dst)
+                        .map_err(|e| Error::Config(format!("Couldn't tell the container of {} from its name, set format: {e}", dst.display())))?,
+                };
+                let (codec, dst_fmt) = video_encoder(options, dst, &out_ctx, &mut log)?;
+                Some((out_ctx, dst.as_path(), codec, dst_fmt))
+            }
+            None => None,
+        };
+
+        // Adds the output streams in the order of the input
+        let mut stream_mapping = vec![-1; in_ctx.nb_streams() as _];
+        let mut in_stream_tbs = vec![Rational(0, 1); in_ctx.nb_streams() as _];
+        let mut pipelines: Vec<VideoPipeline> = Vec::with_capacity(filtered.len());
+        let mut audio_transcoders: Vec<Option<AudioTranscoder>> = (0..in_ctx.nb_streams()).map(|_| None).collect();
+        let mut subtitle_transcoders: Vec<Option<SubtitleTranscoder>> = (0..in_ctx.nb_streams()).map(|_| None).collect();
+        let mut caption_decoder = None;
+        let mut total_frames = 0;
+        let mut out_stream_idx = 0;
+        for (stream_idx, in_stream) in in_ctx.streams().enumerate() {
+            if filtered.contains(&stream_idx) {
+                let video_output = output.as_mut().map(|(out_ctx, _, codec, dst_fmt)| (out_ctx, *codec, *dst_fmt));
+                let mut pipeline = VideoPipeline::new(options, &in_stream, char_set.clone(), video_output)?;
+                if let Some((black, white)) = levels_ranges[stream_idx] {
+                    pipeline.render.renderer.set_levels_range(black, white);
+                }
+                pipelines.push(pipeline);
+                total_frames += frame_count(&in_stream, in_ctx.duration());
+                if output.is_some() {
+                    stream_mapping[stream_idx] = out_stream_idx;
+                    out_stream_idx += 1;
+                }
+                continue;
+            }
+            if Some(stream_idx) == burn_stream {
+                // Burned subtitles are decoded but get no stream of their own
+                caption_decoder = Some(CaptionDecoder::new(&in_stream)?);
+                continue;
+            }
+            // Other streams only go to a video output
+            let Some((out_ctx, dst, _, _)) = output.as_mut() else {
+                continue;
+            };
+
+            let media = in_stream.parameters().medium();
+            let in_codec = in_stream.parameters().id();
+            let can_hold = can_hold(out_ctx, in_codec);
+            if !options.streams.keeps(&in_stream) {
+                log(format!("Dropping {media:?} stream {stream_idx}, it isn't selected"));
+            } else if media == media::Type::Subtitle && !can_hold && subtitle::is_text(in_codec) {
+                let codec = match subtitle_encoder(dst, out_ctx) {
+                    Ok(codec) => codec,
+                    Err(e) => {
+                        log(format!("Dropping subtitle stream {stream_idx} ({}): {e}", in_codec.name()));
+                        continue;
+                    }
+                };
+                match SubtitleTranscoder::new(&in_stream, codec, out_ctx) {
+                    Ok(subtitle) => subtitle_transcoders[stream_idx] = Some(subtitle),
+                    Err(e) => {
+                        log(format!("Dropping subtitle stream {stream_idx} ({}): {} can't encode it: {e}", in_codec.name(), codec.name()));
+                        continue;
+                    }
+                }
+                log(format!("Converting subtitle stream {stream_idx} from {} to {}", in_codec.name(), codec.name()));
+                stream_mapping[stream_idx] = out_stream_idx;
+                out_stream_idx += 1;
+            } else if media == media::Type::Audio && (options.audio_codec.is_some() || !can_hold) {
+                // A configured codec has to work, the container default is only tried
+                let codec = match audio_encoder(options, dst, out_ctx) {
+                    Ok(codec) => codec,
+                    Err(e) if options.audio_codec.is_some() => return Err(e),
+                    Err(e) => {
+                        log(format!("Dropping audio stream {stream_idx} ({}): {e}", in_codec.name()));
+                        continue;
+                    }
+                };
+                // Encoders that can't be opened with any layout or rate lose the stream, not the whole render
+                match AudioTranscoder::new(&in_stream, codec, options.audio_bit_rate, out_ctx) {
+                    Ok(audio) => audio_transcoders[stream_idx] = Some(audio),
+                    Err(e) => {
+                        log(format!("Dropping audio stream {stream_idx} ({}): {} can't encode it: {e}", in_codec.name(), codec.name()));
+                        continue;
+                    }
+                }
+                log(format!("Converting audio stream {stream_idx} from {} to {}", in_codec.name(), codec.name()));
+                stream_mapping[stream_idx] = out_stream_idx;
+                out_stream_idx += 1;
+            } else if media != media::Type::Unknown && can_hold {
+                // Creates copy of other streams, including unfiltered video and attached pictures
+                let mut out_stream = out_ctx.add_stream(encoder::find(codec::Id::None))?;
+                out_stream.set_parameters(in_stream.parameters());
+                copy_stream_tags(&in_stream, &mut out_stream);
+                unsafe {
+                    (*out_stream.parameters_mut().as_mut_ptr()).codec_tag = 0;
+                }
+                out_stream.set_time_base(in_stream.time_base());
+                in_stream_tbs[stream_idx] = in_stream.time_base();
+                stream_mapping[stream_idx] = out_stream_idx;
+                out_stream_idx += 1;
+            } else {
+                log(format!("Dropping {media:?} stream {stream_idx}, {} can't hold {}", out_ctx.format().name(), in_codec.name()));
+            }
+        }
+
+        // Write header
+        let mut out_ctx = output.map(|(out_ctx, ..)| out_ctx);
+        let mut out_stream_tbs: Vec<Rational> = Vec::new();
+        if let Some(out_ctx) = out_ctx.as_mut() {
+            for chapter in in_ctx.chapters() {
+                if let Some(title) = chapter.metadata().get("title") {
+                    out_ctx.add_chapter(chapter.id(), chapter.time_base(), chapter.start(), chapter.end(), title)?;
+                }
+            }
+            out_ctx.set_metadata(in_ctx.metadata().to_owned());
+            out_ctx.write_header_with(muxer_options(options, out_ctx))?;
+            out_stream_tbs = out_ctx.streams().map(|stream| stream.time_base()).collect();
+        }
+        set_out_time_bases(&mut audio_transcoders, &stream_mapping, &out_stream_tbs);
+        set_out_time_bases(&mut subtitle_transcoders, &stream_mapping, &out_stream_tbs);
+
+        // Decoding, rendering and encoding of every filtered stream each run on their own thread, this one demuxes and muxes.
+        // Encoded packets come back on an unbounded channel so the muxer never blocks the encoders.
+        let frame_ct = AtomicU64::new(0);
+        let (encoded_tx, encoded_rx) = channel::<Packet>();
+        let mut cancelled = false;
+        thread::scope(|scope| -> Result<(), Error> {
+            let mut packet_txs: Vec<Option<SyncSender<Packet>>> = (0..stream_mapping.len()).map(|_| None).collect();
+            let mut caption_txs = Vec::with_capacity(pipelines.len());
+            let mut stages = Vec::with_capacity(3 * pipelines.len());
+            for VideoPipeline { in_stream_idx, decoder, render, encoder } in pipelines {
+                let (packet_tx, packet_rx) = sync_channel(PIPELINE_DEPTH);
+                let (scaled_tx, scaled_rx) = sync_channel(PIPELINE_DEPTH);
+                let (caption_tx, caption_rx) = channel();
+                let rendered_tx = encoder.map(|(encoder, out_stream_idx)| {
+                    let (rendered_tx, rendered_rx) = sync_channel(PIPELINE_DEPTH);
+                    let encoded_tx = encoded_tx.clone();
+                    let (in_tb, out_tb) = (render.in_vid_tb, out_stream_tbs[out_stream_idx]);
+                    stages.push(("Encode", scope.spawn(move || encode_stream(encoder, rendered_rx, encoded_tx, out_stream_idx, in_tb, out_tb))));
+                    rendered_tx
+                });
+                let frame_ct = &frame_ct;
+                stages.push(("Decode", scope.spawn(move || decoder.run(packet_rx, scaled_tx))));
+                stages.push(("Render", scope.spawn(move || render.run(scaled_rx, caption_rx, rendered_tx, frame_ct))));
+                packet_txs[in_stream_idx] = Some(packet_tx);
+                caption_txs.push(caption_tx);
+            }
+            drop(encoded_tx);
+
+            // Parses video
+            for (stream, mut packet) in in_ctx.packets().filter_map(Result::ok) {
+                if self.cancel.is_cancelled() {
+                    cancelled = true;
+                    break;
+                }
+                if let Some(out_ctx) = out_ctx.as_mut() {
+                    for encoded in encoded_rx.try_iter() {
+                        encoded.write_interleaved(out_ctx)?;
+                    }
+                }
+
+                // Parses packets that don't have an out stream
+                let in_stream_idx = stream.index();
+                if let Some(caption_decoder) = caption_decoder.as_mut().filter(|_| Some(in_stream_idx) == burn_stream) {
+                    if let Some(caption) = caption_decoder.decode(&packet)? {
+                        // A render stage only stops early when it failed, its error is returned when it is joined
+                        for caption_tx in &caption_txs {
+                            let _ = caption_tx.send(caption.clone());
+                        }
+                    }
+                    continue;
+                }
+                if let Some(packet_tx) = &packet_txs[in_stream_idx] {
+                    // A closed channel means the decoder failed, its error is returned when it is joined
+                    if packet_tx.send(packet).is_err() {
+                        break;
+                    }
+                } else if let (Some(out_ctx), Ok(out_stream_idx)) = (out_ctx.as_mut(), usize::try_from(stream_mapping[in_stream_idx])) {
+                    if let Some(audio) = audio_transcoders[in_stream_idx].as_mut() {
+                        audio.send_packet(packet, out_ctx)?;
+                    } else if let Some(subtitle) = subtitle_transcoders[in_stream_idx].as_mut() {
+                        subtitle.send_packet(packet, out_ctx)?;
+                    } else {
+                        packet.rescale_ts(in_stream_tbs[in_stream_idx], out_stream_tbs[out_stream_idx]);
+                        packet.set_position(-1);
+                        packet.set_stream(out_stream_idx);
+                        packet.write_interleaved(out_ctx)?;
+                    }
+                }
+
+                // Logging
+                if last_t.elapsed() > self.progress_interval {
+                    if let Some(on_progress) = self.on_progress.as_mut() {
+                        on_progress(Progress { frames: frame_ct.load(Ordering::Relaxed), total_frames, elapsed: start_t.elapsed() });
+                    }
+                    last_t = Instant::now();
+                }
+            }
+
+            // Closing the first channels flushes every stage in order
+            drop(packet_txs);
+            drop(caption_txs);
+            if let Some(out_ctx) = out_ctx.as_mut() {
+                for encoded in encoded_rx {
+                    encoded.write_interleaved(out_ctx)?;
+                }
+            }
+            for (name, stage) in stages {
+                stage.join().unwrap_or_else(|_| panic!("{name} stage panicked"))?;
+            }
+            Ok(())
+        })?;
+
+        // Close file
+        if let Some(out_ctx) = out_ctx.as_mut() {
+            for audio in audio_transcoders.iter_mut().flatten() {
+                audio.finish(out_ctx)?;
+            }
+            out_ctx.write_trailer()?;
+        }
+
+        let progress = Progress { frames: frame_ct.into_inner(), total_frames, elapsed: start_t.elapsed() };
+        if let Some(on_progress) = self.on_progress.as_mut() {
+            on_progress(progress);
+        }
+        if cancelled {
+            return Err(Error::Cancelled);
+        }
+        Ok(progress)
+    }
+}